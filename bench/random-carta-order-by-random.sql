@@ -0,0 +1,7 @@
+-- The query `fetch_random_carta` used to run, ignoring 100 already peeked cartas
+select * from cartas
+where random_accessible = true
+    and id <> all (array(select generate_series(0, 396, 4)))
+    and lang = any (array['en'])
+order by random()
+limit 1;
@@ -0,0 +1,14 @@
+-- The queries `fetch_random_carta` runs: find the highest id, then sample a page of
+-- cartas the user hasn't seen upward from a random pivot
+select id from cartas order by id desc limit 1;
+\set pivot random(0, :rows)
+select * from cartas
+where random_accessible
+    and lang = any (array['en'])
+    and id >= :pivot
+    and not exists (
+        select 1 from seen_cartas
+        where seen_cartas.user_id = 1 and seen_cartas.carta_id = cartas.id
+    )
+order by id
+limit 32;
@@ -0,0 +1,40 @@
+#!/bin/sh
+# Benchmark peeking a random carta with the old `order by random()` query and the
+# sampling query used by `Database::fetch_random_carta`.
+# usage: bench/random-carta.sh [number of cartas] [seconds per query]
+
+ROWS=${1:-1000000}
+SECONDS_PER_QUERY=${2:-10}
+DATABASE=abyss_bench
+export PGOPTIONS="--client-min-messages=warning"
+cd "$(dirname "$0")/.." || exit 1
+
+psql -q -c "DROP DATABASE IF EXISTS $DATABASE;"
+psql -q -c "CREATE DATABASE $DATABASE;" || exit 1
+for migration in migrations/*/up.sql; do
+    psql -q -v ON_ERROR_STOP=1 -d $DATABASE -f "$migration" >/dev/null || exit 1
+done
+
+# Every fourth carta is top-level, the rest reply to an earlier carta
+echo "inserting $ROWS cartas..."
+psql -q -v ON_ERROR_STOP=1 -d $DATABASE -c "
+    insert into cartas (uuid, parent, content, modification_code, creation, lang, random_accessible, reports, ip)
+    select
+        gen_random_uuid(),
+        case when n % 4 = 0 then null else (random() * (n - 1))::integer end,
+        'benchmark',
+        '000000',
+        now() - n * interval '1 second',
+        'en',
+        n % 4 = 0,
+        0,
+        '127.0.0.1'
+    from generate_series(1, $ROWS) as n;
+    analyze cartas;" || exit 1
+
+for script in bench/random-carta-*.sql; do
+    echo "== $script"
+    pgbench -n -f "$script" -T "$SECONDS_PER_QUERY" -D rows="$ROWS" $DATABASE | grep -E "latency|tps"
+done
+
+psql -q -c "DROP DATABASE $DATABASE;"
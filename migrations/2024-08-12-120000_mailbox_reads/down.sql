@@ -0,0 +1 @@
+drop table if exists mailbox_reads;
@@ -0,0 +1,6 @@
+drop table if exists mailbox_reads;
+
+create table mailbox_reads (
+    user_id integer primary key not null, -- mailbox owner
+    last_read integer not null -- unix timestamp. replies created after this are unread
+)
@@ -0,0 +1,2 @@
+alter table cartas
+    add column visibility smallint not null default 0; -- 0 is public, 1 is private to the replier and the parent's author
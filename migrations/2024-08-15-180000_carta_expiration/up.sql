@@ -0,0 +1,2 @@
+alter table cartas
+    add column expiration integer; -- unix timestamp. null never expires
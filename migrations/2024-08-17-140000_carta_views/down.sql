@@ -0,0 +1 @@
+alter table cartas drop column if exists views;
@@ -0,0 +1,2 @@
+alter table cartas
+    add column views integer not null default 0; -- times shown when peeking into the abyss
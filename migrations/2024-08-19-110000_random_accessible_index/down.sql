@@ -0,0 +1 @@
+drop index if exists cartas_random_accessible_id;
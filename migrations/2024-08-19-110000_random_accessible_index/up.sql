@@ -0,0 +1,2 @@
+-- lets carta sampling scan only cartas that can be peeked at, in id order
+create index cartas_random_accessible_id on cartas (id) where random_accessible;
@@ -0,0 +1,7 @@
+drop table if exists seen_cartas;
+
+create table seen_cartas (
+    user_id integer not null,
+    carta_id integer not null,
+    primary key (user_id, carta_id)
+)
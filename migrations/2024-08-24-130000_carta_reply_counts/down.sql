@@ -0,0 +1,3 @@
+alter table cartas
+    drop column if exists replies,
+    drop column if exists last_reply;
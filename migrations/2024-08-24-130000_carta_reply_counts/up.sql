@@ -0,0 +1,13 @@
+alter table cartas
+    add column replies integer not null default 0, -- number of public replies
+    add column last_reply integer; -- unix timestamp of the newest public reply
+
+update cartas set
+    replies = (
+        select count(*) from cartas as reply
+        where reply.parent = cartas.id and reply.visibility = 0
+    ),
+    last_reply = (
+        select max(reply.creation) from cartas as reply
+        where reply.parent = cartas.id and reply.visibility = 0
+    );
@@ -0,0 +1,11 @@
+alter table mailbox_reads
+    alter column last_read type integer using extract(epoch from last_read)::integer;
+
+alter table cartas
+    alter column creation type integer using extract(epoch from creation)::integer,
+    alter column modification type integer using extract(epoch from modification)::integer,
+    alter column expiration type integer using extract(epoch from expiration)::integer,
+    alter column last_reply type integer using extract(epoch from last_reply)::integer;
+
+alter table users
+    alter column creation type integer using extract(epoch from creation)::integer;
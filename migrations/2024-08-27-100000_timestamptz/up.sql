@@ -0,0 +1,11 @@
+alter table users
+    alter column creation type timestamptz using to_timestamp(creation);
+
+alter table cartas
+    alter column creation type timestamptz using to_timestamp(creation),
+    alter column modification type timestamptz using to_timestamp(modification),
+    alter column expiration type timestamptz using to_timestamp(expiration),
+    alter column last_reply type timestamptz using to_timestamp(last_reply);
+
+alter table mailbox_reads
+    alter column last_read type timestamptz using to_timestamp(last_read);
@@ -0,0 +1,7 @@
+drop index if exists cartas_lang_random_accessible;
+drop index if exists cartas_user_id;
+drop index if exists cartas_parent;
+drop index if exists cartas_uuid;
+
+alter table cartas
+    alter column uuid type char(36) using uuid::text;
@@ -0,0 +1,10 @@
+alter table cartas
+    alter column uuid type uuid using uuid::uuid;
+
+create unique index cartas_uuid on cartas (uuid);
+-- replies of a carta, when building threads and counting replies
+create index cartas_parent on cartas (parent);
+-- cartas of a user, for their listing and mailbox
+create index cartas_user_id on cartas (user_id);
+-- lets random sampling skip cartas in other languages
+create index cartas_lang_random_accessible on cartas (lang, random_accessible);
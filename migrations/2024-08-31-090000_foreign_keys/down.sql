@@ -0,0 +1,13 @@
+alter table mailbox_reads
+    drop constraint if exists mailbox_reads_user_id_fkey;
+
+alter table seen_cartas
+    drop constraint if exists seen_cartas_carta_id_fkey,
+    drop constraint if exists seen_cartas_user_id_fkey;
+
+alter table cartas
+    drop constraint if exists cartas_user_id_fkey,
+    drop constraint if exists cartas_parent_fkey;
+
+-- merged users aren't split back up
+drop index if exists users_certificate_hash;
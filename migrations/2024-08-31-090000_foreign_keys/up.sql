@@ -0,0 +1,49 @@
+-- merge users sharing a certificate into the first one created
+create temporary table user_merges as
+    select id as duplicate, min(id) over (partition by certificate_hash) as kept
+    from users;
+delete from user_merges where duplicate = kept;
+
+update cartas set user_id = kept
+    from user_merges where user_id = duplicate;
+
+insert into seen_cartas (user_id, carta_id)
+    select kept, carta_id from seen_cartas join user_merges on user_id = duplicate
+    on conflict do nothing;
+delete from seen_cartas using user_merges where user_id = duplicate;
+
+insert into mailbox_reads (user_id, last_read)
+    select kept, max(last_read) from mailbox_reads join user_merges on user_id = duplicate
+    group by kept
+    on conflict (user_id) do update
+        set last_read = greatest(mailbox_reads.last_read, excluded.last_read);
+delete from mailbox_reads using user_merges where user_id = duplicate;
+
+delete from users using user_merges where id = duplicate;
+drop table user_merges;
+
+create unique index users_certificate_hash on users (certificate_hash);
+
+-- drop dangling references left from before they were enforced. replies whose
+-- parent is gone are kept as top-level cartas rather than deleted
+update cartas set parent = null where parent not in (select id from cartas);
+update cartas set user_id = null where user_id not in (select id from users);
+delete from seen_cartas
+    where user_id not in (select id from users) or carta_id not in (select id from cartas);
+delete from mailbox_reads where user_id not in (select id from users);
+
+alter table cartas
+    add constraint cartas_parent_fkey
+        foreign key (parent) references cartas (id) on delete cascade,
+    add constraint cartas_user_id_fkey
+        foreign key (user_id) references users (id) on delete set null;
+
+alter table seen_cartas
+    add constraint seen_cartas_user_id_fkey
+        foreign key (user_id) references users (id) on delete cascade,
+    add constraint seen_cartas_carta_id_fkey
+        foreign key (carta_id) references cartas (id) on delete cascade;
+
+alter table mailbox_reads
+    add constraint mailbox_reads_user_id_fkey
+        foreign key (user_id) references users (id) on delete cascade;
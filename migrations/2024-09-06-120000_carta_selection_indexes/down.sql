@@ -0,0 +1,2 @@
+drop index if exists cartas_random_accessible_views;
+drop index if exists cartas_random_accessible_replies;
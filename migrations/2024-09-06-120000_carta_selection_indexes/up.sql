@@ -0,0 +1,4 @@
+-- let the unanswered and least-shown strategies walk cartas that can be peeked at
+-- from the fewest replies or views up
+create index cartas_random_accessible_replies on cartas (replies, id) where random_accessible;
+create index cartas_random_accessible_views on cartas (views, id) where random_accessible;
@@ -0,0 +1,4 @@
+drop table if exists seen_cartas;
+drop table if exists mailbox_reads;
+drop table if exists cartas;
+drop table if exists users;
@@ -0,0 +1,72 @@
+-- the schema of `migrations/` as of 2024-08-31-090000_foreign_keys, in one go.
+-- sqlite has no uuid type, so uuids are stored as text, and timestamps are text
+-- in utc, which sorts and compares in time order
+
+create table users (
+    id integer primary key not null, -- sequential
+    certificate_hash blob not null, -- sha256 hash
+    lang text not null, -- language code
+    creation timestamptz not null
+);
+
+create unique index users_certificate_hash on users (certificate_hash);
+
+create table cartas (
+    id integer primary key not null, -- sequential
+    uuid text not null,
+    parent integer references cartas (id) on delete cascade, -- null designates root node
+    user_id integer references users (id) on delete set null, -- null designates anonymous
+    title text, -- null for unknown
+    sender text, -- null for unknown
+    content text not null,
+    modification_code text not null, -- 6-digit "pin"
+    creation timestamptz not null,
+    modification timestamptz,
+    lang text not null, -- language code
+    random_accessible boolean not null,
+    reports integer not null,
+    ip text not null,
+    visibility smallint not null default 0, -- 0 is public, 1 is private to the replier and the parent's author
+    expiration timestamptz, -- null never expires
+    views integer not null default 0, -- times shown when peeking into the abyss
+    replies integer not null default 0, -- number of public replies
+    last_reply timestamptz -- newest public reply
+);
+
+create unique index cartas_uuid on cartas (uuid);
+create index cartas_parent on cartas (parent);
+create index cartas_user_id on cartas (user_id);
+create index cartas_lang_random_accessible on cartas (lang, random_accessible);
+-- lets carta sampling scan only cartas that can be peeked at, in id order
+create index cartas_random_accessible_id on cartas (id) where random_accessible;
+
+create table mailbox_reads (
+    user_id integer primary key not null references users (id) on delete cascade, -- mailbox owner
+    last_read timestamptz not null -- replies created after this are unread
+);
+
+create table seen_cartas (
+    user_id integer not null references users (id) on delete cascade,
+    carta_id integer not null references cartas (id) on delete cascade,
+    primary key (user_id, carta_id)
+);
+
+insert into cartas (
+    id, uuid, parent, user_id, title, sender, content, modification_code,
+    creation, modification, lang, random_accessible, reports, ip
+) values (
+    0,
+    '00000000-0000-0000-0000-000000000000',
+    null,
+    null,
+    'welcome',
+    'sheepy',
+    'welcome to the Abyss. i hope you enjoy your stay.',
+    '370569',
+    '2024-08-05 21:18:58+00:00',
+    null,
+    'en',
+    false,
+    0,
+    '127.0.0.1'
+);
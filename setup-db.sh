@@ -1,7 +1,6 @@
 #!/bin/sh
 
-psql -c "DROP DATABASE abyss;"
-psql -c "CREATE DATABASE abyss;" || exit 1
-diesel migration run || exit 1
-diesel migration redo -a || exit 1 # sanity check!!
+# The schema itself is migrated by abyss on startup; this only creates the database
+psql -lqt | cut -d '|' -f 1 | grep -qw abyss || psql -c "CREATE DATABASE abyss;" || exit 1
+cargo run --release -- --migrate-only || exit 1
 echo "success!!"
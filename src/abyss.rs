@@ -6,19 +6,22 @@ use crate::{
             submit_carta::{handle_submit_confirmation, handle_submit_new},
             view_carta::handle_viewing_carta,
             view_cartas::handle_viewing_cartas,
+            view_mailbox::handle_viewing_mailbox,
+            view_thread::handle_viewing_thread,
             write_carta::handle_writing_carta,
         },
     },
-    consts::{DEFAULT_CARTA, MAX_FROM_LEN, MAX_LINE_LEN, MAX_TITLE_LEN},
-    database::{Carta, DatabaseCache, DATABASE, DATABASE_CACHE},
+    consts::{CARTA_SELECTION, MAX_FROM_LEN, MAX_LINE_LEN, MAX_PEEK_HISTORY, MAX_TITLE_LEN},
+    database::{Carta, CartaFilter, CartaSort, Database, DATABASE_CACHE},
     i18n::Lang,
     state::ClientState,
 };
 
 use anyhow::{anyhow, Context as _};
-use std::{collections::VecDeque, sync::Arc};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
 use twinstar::Document;
 use urlencoding::decode;
+use uuid::Uuid;
 use windmark::context::RouteContext;
 
 /// Helper function to validate an input's length
@@ -33,7 +36,7 @@ fn validate_len(
         client.abyss_state.to_flash.push(format!(
             "{write_too_long} ({actual_len}/{len}): {input}",
             actual_len = input.len(),
-            write_too_long = &client.lang.write_too_long,
+            write_too_long = client.lang.write_too_long,
             input = input
         ));
         return client.redirect_to_abyss().ok();
@@ -45,15 +48,32 @@ fn validate_len(
 pub struct CartaInformation {
     pub id: i32,
     pub carta: Arc<Carta>,
+    /// Pinned cartas are listed first and never dropped from the history
+    pub pinned: bool,
 }
 
 #[derive(Default)]
 pub struct AbyssState {
+    /// Newest first, holding at most [`MAX_PEEK_HISTORY`] unpinned cartas
     pub top_level_cartas_loaded: VecDeque<CartaInformation>,
+    /// Zero-indexed page of the history shown on the fetch page
+    pub fetch_page: usize,
     pub currently: AbyssMode,
     pub to_flash: Vec<String>,
     pub languages: Vec<String>,
     pub write_state: AbyssWriteState,
+    pub view_state: AbyssViewState,
+    /// UUID of the carta whose replies are all shown, past `THREAD_MAX_SIBLINGS`
+    pub expanded_carta: Option<Uuid>,
+    pub thread_order: ThreadOrder,
+}
+/// How the client's own cartas are listed
+#[derive(Default)]
+pub struct AbyssViewState {
+    /// Zero-indexed
+    pub page: usize,
+    pub sort: CartaSort,
+    pub filter: CartaFilter,
 }
 #[derive(Default)]
 pub struct AbyssWriteState {
@@ -61,19 +81,106 @@ pub struct AbyssWriteState {
     pub hide_line_numbers: bool,
     pub title: Option<String>,
     pub from: Option<String>,
-    pub reply: Option<String>,
+    pub reply: Option<Uuid>,
+    /// Only the author of the carta being replied to can read the reply
+    pub private: bool,
+    pub lifetime: CartaLifetime,
+}
+
+/// How long a carta lives before it's redacted
+#[derive(Default, Clone, Copy)]
+pub enum CartaLifetime {
+    #[default]
+    Forever,
+    Day,
+    Week,
+}
+impl CartaLifetime {
+    /// Cycle to the next lifetime option
+    pub fn next(self) -> Self {
+        match self {
+            Self::Forever => Self::Day,
+            Self::Day => Self::Week,
+            Self::Week => Self::Forever,
+        }
+    }
+    pub fn duration(self) -> Option<Duration> {
+        match self {
+            Self::Forever => None,
+            Self::Day => Some(Duration::from_secs(60 * 60 * 24)),
+            Self::Week => Some(Duration::from_secs(60 * 60 * 24 * 7)),
+        }
+    }
+    pub fn display(self, lang: &Lang) -> &str {
+        match self {
+            Self::Forever => &lang.lifetime_forever,
+            Self::Day => &lang.lifetime_day,
+            Self::Week => &lang.lifetime_week,
+        }
+    }
+}
+/// Order the cartas of a thread are listed in on the thread page
+#[derive(Default, Clone, Copy)]
+pub enum ThreadOrder {
+    #[default]
+    Tree,
+    Chronological,
+}
+impl ThreadOrder {
+    /// Cycle to the next order
+    pub fn next(self) -> Self {
+        match self {
+            Self::Tree => Self::Chronological,
+            Self::Chronological => Self::Tree,
+        }
+    }
+    pub fn display(self, lang: &Lang) -> &str {
+        match self {
+            Self::Tree => &lang.thread_order_tree,
+            Self::Chronological => &lang.thread_order_chronological,
+        }
+    }
 }
 impl AbyssState {
-    pub fn new(lang: &Lang) -> Self {
+    pub fn new(lang: &Lang, default_carta: Arc<Carta>) -> Self {
         Self {
             languages: vec![lang.code.clone()],
             top_level_cartas_loaded: VecDeque::from_iter([CartaInformation {
                 id: 1,
-                carta: Arc::clone(&DEFAULT_CARTA),
+                carta: default_carta,
+                pinned: false,
             }]),
             ..Default::default()
         }
     }
+
+    /// Add a peeked carta to the front of the history, dropping the oldest unpinned
+    /// carta once the history is full
+    pub fn push_loaded(&mut self, carta_info: CartaInformation) {
+        self.top_level_cartas_loaded.push_front(carta_info);
+        let unpinned = self
+            .top_level_cartas_loaded
+            .iter()
+            .filter(|info| !info.pinned)
+            .count();
+        if unpinned > MAX_PEEK_HISTORY {
+            if let Some(oldest) = self
+                .top_level_cartas_loaded
+                .iter()
+                .rposition(|info| !info.pinned)
+            {
+                self.top_level_cartas_loaded.remove(oldest);
+            }
+        }
+        self.fetch_page = 0;
+    }
+
+    /// Find a carta in the history from its UUID
+    pub fn loaded_mut(&mut self, uuid: Uuid) -> Option<&mut CartaInformation> {
+        self.top_level_cartas_loaded
+            .iter_mut()
+            .find(|info| info.carta.uuid == uuid)
+    }
 }
 
 #[derive(Default, Clone)]
@@ -81,48 +188,42 @@ pub enum AbyssMode {
     #[default]
     FetchingCartas,
     WritingCarta,
-    ReplyingCarta(String), // uuid
+    ReplyingCarta(Uuid),
     ViewingCartas,
-    ViewingCarta(String), // uuid
+    ViewingCarta(Uuid),
+    ViewingThread(Uuid),
+    ViewingMailbox,
 }
 
 /// Fetch a carta's title and ID. An id of None designates a random carta to be fetched.
-fn fetch_carta(client: &ClientState, id: Option<i32>) -> anyhow::Result<Option<CartaInformation>> {
-    let mut database_guard = DATABASE
-        .lock()
-        .map_err(|_| anyhow!("failed to lock database mutex"))?;
-
-    let carta = if let Some(id) = id {
-        Some(database_guard.fetch_carta(id)?)
-    } else {
-        database_guard.fetch_random_carta(
-            &client.abyss_state.languages,
-            client
-                .abyss_state
-                .top_level_cartas_loaded
-                .iter()
-                .map(|info| info.carta.id),
-        )?
-    };
+async fn fetch_carta(
+    client: &ClientState,
+    id: Option<i32>,
+) -> anyhow::Result<Option<CartaInformation>> {
+    let languages = client.abyss_state.languages.clone();
+    let user_id = client.id() as _;
+    let since = DATABASE_CACHE.carta.generation()?;
+    let carta = Database::run(move |connection| match id {
+        Some(id) => Database::fetch_carta(connection, id).map(Some),
+        None => Database::fetch_random_carta(connection, &languages, user_id, *CARTA_SELECTION),
+    })
+    .await?;
 
     if let Some(carta) = carta {
-        let carta = DatabaseCache::insert_cache(&DATABASE_CACHE.carta, &carta.uuid.clone(), carta)?;
+        let uuid = carta.uuid;
+        let carta = DATABASE_CACHE.carta.insert(uuid, carta, since)?;
         return Ok(Some(CartaInformation {
             id: carta.id,
             carta,
+            pinned: false,
         }));
     }
     Ok(None)
 }
 /// Peek into the abyss
-fn handle_peek_state_change(client: &mut ClientState) -> anyhow::Result<AbyssMode> {
-    match fetch_carta(client, None)? {
-        Some(carta_info) => {
-            client
-                .abyss_state
-                .top_level_cartas_loaded
-                .push_front(carta_info);
-        }
+async fn handle_peek_state_change(client: &mut ClientState) -> anyhow::Result<AbyssMode> {
+    match fetch_carta(client, None).await? {
+        Some(carta_info) => client.abyss_state.push_loaded(carta_info),
         None => {
             client
                 .abyss_state
@@ -218,11 +319,8 @@ fn handle_change_field(
     ))
 }
 /// Handle reporting a carta
-fn handle_report_carta(client: &mut ClientState, uuid: &str) -> anyhow::Result<()> {
-    let mut database_guard = DATABASE
-        .lock()
-        .map_err(|_| anyhow!("failed to lock database mutex"))?;
-    database_guard.report_carta(uuid)?;
+async fn handle_report_carta(client: &mut ClientState, uuid: Uuid) -> anyhow::Result<()> {
+    Database::run(move |connection| Database::report_carta(connection, uuid)).await?;
     client
         .abyss_state
         .to_flash
@@ -230,8 +328,39 @@ fn handle_report_carta(client: &mut ClientState, uuid: &str) -> anyhow::Result<(
     Ok(())
 }
 
+/// Handle forgetting which cartas the client has seen
+async fn handle_forget_seen(client: &mut ClientState) -> anyhow::Result<()> {
+    let user_id = client.id() as _;
+    Database::run(move |connection| Database::forget_seen_cartas(connection, user_id)).await?;
+    client
+        .abyss_state
+        .to_flash
+        .push(client.lang.forget_seen_flash.clone());
+    Ok(())
+}
+/// Handle opening the mailbox, which is only kept for clients with a certificate
+fn handle_mailbox_state_change(client: &mut ClientState) -> AbyssMode {
+    if !client.certificate {
+        client
+            .abyss_state
+            .to_flash
+            .push(client.lang.mailbox_cert_required_flash.clone());
+        return AbyssMode::FetchingCartas;
+    }
+    AbyssMode::ViewingMailbox
+}
+/// Handle marking all replies in the mailbox as read
+async fn handle_mark_read(client: &mut ClientState) -> anyhow::Result<()> {
+    if !client.certificate {
+        return Ok(());
+    }
+    let user_id = client.id() as _;
+    Database::run(move |connection| Database::mark_replies_read(connection, user_id)).await?;
+    Ok(())
+}
+
 /// `/abyss` endpoint
-pub fn handle_client_in_abyss(
+pub async fn handle_client_in_abyss(
     context: RouteContext,
     lang: &'static Lang,
     certificate: bool,
@@ -259,16 +388,15 @@ pub fn handle_client_in_abyss(
     };
 
     // Lookup or create new client
-    let (id, client) = ClientState::lookup_from_identifier(&identifier)?
-        .map(Ok::<_, anyhow::Error>)
-        .unwrap_or_else(|| ClientState::init_state(&identifier, lang, certificate))?;
-    let mut client = client
-        .lock()
-        .map_err(|_| anyhow!("failed to lock client mutex"))?;
+    let (id, client) = match ClientState::lookup_from_identifier(&identifier)? {
+        Some(client) => client,
+        None => ClientState::init_state(&identifier, lang, certificate).await?,
+    };
+    let mut client = client.lock().await;
     client.poke();
 
     if client.lang.code != lang.code {
-        client.update_lang(lang)?;
+        client.update_lang(lang).await?;
     }
 
     log::debug!("handling client with id {id} in abyss");
@@ -277,8 +405,21 @@ pub fn handle_client_in_abyss(
     if let Some(state) = context.parameters.get("state").map(String::as_str) {
         match state {
             "fetch" => client.abyss_state.currently = AbyssMode::FetchingCartas,
-            "peek" => client.abyss_state.currently = handle_peek_state_change(&mut client)?,
+            "peek" => client.abyss_state.currently = handle_peek_state_change(&mut client).await?,
+            "forget" => handle_forget_seen(&mut client).await?,
             "view" => client.abyss_state.currently = AbyssMode::ViewingCartas,
+            "view-sort" => {
+                let view_state = &mut client.abyss_state.view_state;
+                view_state.sort = view_state.sort.next();
+                view_state.page = 0;
+            }
+            "view-kind" => {
+                let view_state = &mut client.abyss_state.view_state;
+                view_state.filter.kind = view_state.filter.kind.next();
+                view_state.page = 0;
+            }
+            "mailbox" => client.abyss_state.currently = handle_mailbox_state_change(&mut client),
+            "mark-read" => handle_mark_read(&mut client).await?,
             "from" => {
                 // "totally safe"
                 let field =
@@ -314,32 +455,74 @@ pub fn handle_client_in_abyss(
                 client.abyss_state.write_state.hide_line_numbers =
                     !client.abyss_state.write_state.hide_line_numbers;
             }
+            "lifetime" => {
+                client.abyss_state.write_state.lifetime =
+                    client.abyss_state.write_state.lifetime.next();
+            }
+            "toggle-private" => {
+                client.abyss_state.write_state.private = !client.abyss_state.write_state.private;
+            }
             "submit-confirmation" => return handle_submit_confirmation(&mut client),
             "submit" => {
-                let reply_uuid = client.abyss_state.write_state.reply.clone();
-                return handle_submit_new(&mut client, &context, reply_uuid);
+                let reply_uuid = client.abyss_state.write_state.reply;
+                return handle_submit_new(&mut client, &context, reply_uuid).await;
             }
             read_carta if state.starts_with("read-") => {
-                let uuid = read_carta.trim_start_matches("read-");
-                client.abyss_state.currently = AbyssMode::ViewingCarta(uuid.to_string());
+                let uuid = read_carta.trim_start_matches("read-").parse()?;
+                client.abyss_state.expanded_carta = None;
+                client.abyss_state.currently = AbyssMode::ViewingCarta(uuid);
+            }
+            expand_carta if state.starts_with("expand-") => {
+                let uuid = expand_carta.trim_start_matches("expand-").parse()?;
+                client.abyss_state.expanded_carta = Some(uuid);
+                client.abyss_state.currently = AbyssMode::ViewingCarta(uuid);
+            }
+            "thread-order" => {
+                client.abyss_state.thread_order = client.abyss_state.thread_order.next();
+            }
+            view_thread if state.starts_with("thread-") => {
+                let uuid = view_thread.trim_start_matches("thread-").parse()?;
+                client.abyss_state.currently = AbyssMode::ViewingThread(uuid);
             }
             reply_carta if state.starts_with("reply-") => {
-                let uuid = reply_carta.trim_start_matches("reply-");
-                if !client
-                    .abyss_state
-                    .write_state
-                    .reply
-                    .as_deref()
-                    .is_some_and(|reply_uuid| reply_uuid == uuid)
-                {
+                let uuid = reply_carta.trim_start_matches("reply-").parse()?;
+                if client.abyss_state.write_state.reply != Some(uuid) {
                     client.abyss_state.write_state = Default::default();
                 }
-                client.abyss_state.write_state.reply = Some(uuid.to_string());
-                client.abyss_state.currently = AbyssMode::ReplyingCarta(uuid.to_string());
+                client.abyss_state.write_state.reply = Some(uuid);
+                client.abyss_state.currently = AbyssMode::ReplyingCarta(uuid);
+            }
+            view_page if state.starts_with("view-page-") => {
+                let page = view_page
+                    .trim_start_matches("view-page-")
+                    .parse::<usize>()?;
+                client.abyss_state.view_state.page = page.saturating_sub(1);
+                client.abyss_state.currently = AbyssMode::ViewingCartas;
+            }
+            page if state.starts_with("page-") => {
+                let page = page.trim_start_matches("page-").parse::<usize>()?;
+                client.abyss_state.fetch_page = page.saturating_sub(1);
+                client.abyss_state.currently = AbyssMode::FetchingCartas;
+            }
+            pin_carta if state.starts_with("pin-") => {
+                let uuid = pin_carta.trim_start_matches("pin-").parse()?;
+                if let Some(carta_info) = client.abyss_state.loaded_mut(uuid) {
+                    carta_info.pinned = !carta_info.pinned;
+                }
+            }
+            dismiss_carta if state.starts_with("dismiss-") => {
+                let uuid = dismiss_carta
+                    .trim_start_matches("dismiss-")
+                    .parse::<Uuid>()?;
+                client
+                    .abyss_state
+                    .top_level_cartas_loaded
+                    .retain(|info| info.carta.uuid != uuid);
+                client.abyss_state.currently = AbyssMode::FetchingCartas;
             }
             report_carta if state.starts_with("report-") => {
-                let uuid = report_carta.trim_start_matches("report-");
-                handle_report_carta(&mut client, uuid)?;
+                let uuid = report_carta.trim_start_matches("report-").parse()?;
+                handle_report_carta(&mut client, uuid).await?;
             }
             _ => (),
         };
@@ -351,17 +534,13 @@ pub fn handle_client_in_abyss(
         flash_document.add_text(flash).add_blank_line();
     }
     let body = match client.abyss_state.currently {
-        AbyssMode::FetchingCartas => handle_fetching_cartas(&mut client)?,
+        AbyssMode::FetchingCartas => handle_fetching_cartas(&mut client).await?,
         AbyssMode::WritingCarta => handle_writing_carta(&mut client, None)?,
-        AbyssMode::ReplyingCarta(ref uuid) => {
-            let uuid = uuid.clone();
-            handle_writing_carta(&mut client, Some(uuid))?
-        }
-        AbyssMode::ViewingCarta(ref uuid) => {
-            let uuid = uuid.clone();
-            handle_viewing_carta(&mut client, uuid)?
-        }
-        AbyssMode::ViewingCartas => handle_viewing_cartas(&mut client)?,
+        AbyssMode::ReplyingCarta(uuid) => handle_writing_carta(&mut client, Some(uuid))?,
+        AbyssMode::ViewingCarta(uuid) => handle_viewing_carta(&mut client, uuid).await?,
+        AbyssMode::ViewingThread(uuid) => handle_viewing_thread(&mut client, uuid).await?,
+        AbyssMode::ViewingCartas => handle_viewing_cartas(&mut client).await?,
+        AbyssMode::ViewingMailbox => handle_viewing_mailbox(&mut client).await?,
     };
     Ok(windmark::response::Response::success(format!(
         "{flash_document}{body}"
@@ -0,0 +1,123 @@
+//! The database backend, picked at compile time with the `postgres` (default) or
+//! `sqlite` feature. SQLite needs no server, which suits trying Abyss out locally
+//! and small deployments.
+
+#[cfg(all(feature = "postgres", feature = "sqlite"))]
+compile_error!(
+    "the `postgres` and `sqlite` features are mutually exclusive, \
+    build with `--no-default-features --features sqlite` for SQLite"
+);
+#[cfg(not(any(feature = "postgres", feature = "sqlite")))]
+compile_error!("either the `postgres` or the `sqlite` feature must be enabled");
+
+#[cfg(feature = "postgres")]
+pub use postgres::*;
+#[cfg(feature = "sqlite")]
+pub use sqlite::*;
+
+#[cfg(feature = "postgres")]
+mod postgres {
+    use crate::consts::DATABASE_STATEMENT_TIMEOUT_MS;
+
+    use diesel::{
+        r2d2::{self, CustomizeConnection},
+        RunQueryDsl as _,
+    };
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+
+    pub type Backend = diesel::pg::Pg;
+    pub type DbConnection = diesel::pg::PgConnection;
+    /// How UUIDs are stored, PostgreSQL having a native type for them
+    pub type SqlUuid = uuid::Uuid;
+
+    /// Migrations in `migrations/`, bundled into the binary
+    pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+    /// Placeholder for the first parameter bound to a raw SQL query
+    pub const FIRST_BIND: &str = "$1";
+
+    /// Set up every new connection with the configured statement timeout
+    #[derive(Debug)]
+    pub struct ConnectionSetup;
+    impl CustomizeConnection<DbConnection, r2d2::Error> for ConnectionSetup {
+        fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), r2d2::Error> {
+            diesel::sql_query(format!(
+                "set statement_timeout = {timeout}",
+                timeout = *DATABASE_STATEMENT_TIMEOUT_MS
+            ))
+            .execute(connection)
+            .map(|_| ())
+            .map_err(r2d2::Error::QueryError)
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use crate::consts::DATABASE_STATEMENT_TIMEOUT_MS;
+
+    use diesel::{
+        connection::SimpleConnection as _,
+        deserialize::{self, FromSql},
+        r2d2::{self, CustomizeConnection},
+        serialize::{self, IsNull, Output, ToSql},
+        sql_types::Text,
+        sqlite::{Sqlite, SqliteValue},
+        AsExpression, FromSqlRow,
+    };
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+    use uuid::Uuid;
+
+    pub type Backend = Sqlite;
+    pub type DbConnection = diesel::sqlite::SqliteConnection;
+
+    /// Migrations in `migrations_sqlite/`, bundled into the binary
+    pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+    /// Placeholder for the first parameter bound to a raw SQL query
+    pub const FIRST_BIND: &str = "?";
+
+    /// Set up every new connection to enforce foreign keys, which SQLite doesn't do
+    /// by default, and to wait on a locked database for up to the statement timeout
+    #[derive(Debug)]
+    pub struct ConnectionSetup;
+    impl CustomizeConnection<DbConnection, r2d2::Error> for ConnectionSetup {
+        fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), r2d2::Error> {
+            connection
+                .batch_execute(&format!(
+                    "pragma journal_mode = wal;
+                    pragma foreign_keys = on;
+                    pragma busy_timeout = {timeout};",
+                    timeout = *DATABASE_STATEMENT_TIMEOUT_MS
+                ))
+                .map_err(r2d2::Error::QueryError)
+        }
+    }
+
+    /// A UUID stored as its hyphenated text, SQLite having no UUID type
+    #[derive(AsExpression, FromSqlRow, Debug)]
+    #[diesel(sql_type = Text)]
+    pub struct SqlUuid(Uuid);
+    impl From<Uuid> for SqlUuid {
+        fn from(uuid: Uuid) -> Self {
+            Self(uuid)
+        }
+    }
+    impl From<SqlUuid> for Uuid {
+        fn from(uuid: SqlUuid) -> Self {
+            uuid.0
+        }
+    }
+    impl FromSql<Text, Sqlite> for SqlUuid {
+        fn from_sql(value: SqliteValue<'_, '_, '_>) -> deserialize::Result<Self> {
+            let text = <String as FromSql<Text, Sqlite>>::from_sql(value)?;
+            Ok(Self(text.parse()?))
+        }
+    }
+    impl ToSql<Text, Sqlite> for SqlUuid {
+        fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Sqlite>) -> serialize::Result {
+            out.set_value(self.0.to_string());
+            Ok(IsNull::No)
+        }
+    }
+}
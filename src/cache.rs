@@ -0,0 +1,425 @@
+//! Bounded in-memory caches for database objects
+
+use anyhow::anyhow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub trait CacheKey: Hash + Eq + Clone {}
+impl<K> CacheKey for K where K: Hash + Eq + Clone {}
+
+struct Entry<T> {
+    store: Arc<T>,
+    creation: Instant,
+    /// Position in [`Entries::recency`]
+    last_used: u64,
+    weight: usize,
+}
+
+struct Entries<K, T> {
+    map: HashMap<K, Entry<T>>,
+    /// Keys by when they were last used, least recently used first
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+    weight: usize,
+    /// Bumped every time entries are invalidated
+    generation: u64,
+}
+impl<K: CacheKey, T> Entries<K, T> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Entry<T>> {
+        let entry = self.map.remove(key)?;
+        self.recency.remove(&entry.last_used);
+        self.weight -= entry.weight;
+        Some(entry)
+    }
+}
+
+/// When something was loaded to be cached, as of the last invalidation. See
+/// [`Cache::generation`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Generation(u64);
+
+/// A snapshot of a cache's size and counters
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub len: usize,
+    pub weight: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Entries dropped to make room for newer ones
+    pub evictions: u64,
+    /// Entries dropped for outliving the cache's time to live
+    pub expirations: u64,
+}
+
+/// A least-recently-used cache holding at most `capacity` worth of entries, each
+/// weighing 1 unless the cache has a weigher, for at most its time to live
+///
+/// Removing entries invalidates them: anything loaded before then, which could be
+/// stale, is refused on insertion. The generation is shared by all entries, so this
+/// errs on the side of caching less.
+pub struct Cache<K, T> {
+    name: &'static str,
+    capacity: usize,
+    ttl: Duration,
+    weigh: fn(&T) -> usize,
+    entries: Mutex<Entries<K, T>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+impl<K: CacheKey, T> Cache<K, T> {
+    pub fn new(name: &'static str, capacity: usize, ttl: Duration) -> Self {
+        Self {
+            name,
+            capacity,
+            ttl,
+            weigh: |_| 1,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                weight: 0,
+                generation: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expirations: AtomicU64::new(0),
+        }
+    }
+
+    /// Weigh entries with a function rather than counting them, so capacity is
+    /// spent according to how large they are
+    pub fn with_weigher(mut self, weigh: fn(&T) -> usize) -> Self {
+        self.weigh = weigh;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn lock(&self) -> anyhow::Result<MutexGuard<'_, Entries<K, T>>> {
+        self.entries
+            .lock()
+            .map_err(|_| anyhow!("failed to lock {name} cache mutex", name = self.name))
+    }
+
+    /// Look up an entry, counting it as used
+    pub fn lookup(&self, key: &K) -> anyhow::Result<Option<Arc<T>>> {
+        let mut entries = self.lock()?;
+
+        let expired = match entries.map.get(key) {
+            Some(entry) => entry.creation.elapsed() > self.ttl,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return Ok(None);
+            }
+        };
+        if expired {
+            entries.remove(key);
+            self.expirations.fetch_add(1, Ordering::Relaxed);
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        }
+
+        let now = entries.tick();
+        let entry = entries.map.get_mut(key).expect("entry was just found");
+        let last_used = std::mem::replace(&mut entry.last_used, now);
+        let store = Arc::clone(&entry.store);
+        entries.recency.remove(&last_used);
+        entries.recency.insert(now, key.clone());
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        Ok(Some(store))
+    }
+
+    /// The current generation, to be taken before loading what will be inserted
+    pub fn generation(&self) -> anyhow::Result<Generation> {
+        Ok(Generation(self.lock()?.generation))
+    }
+
+    /// Insert or replace an entry loaded as of `since`, evicting the least recently
+    /// used ones past capacity. Entries invalidated after `since` may have been
+    /// loaded stale, so they aren't kept, and neither are entries too large for the
+    /// whole cache.
+    pub fn insert(&self, key: K, store: T, since: Generation) -> anyhow::Result<Arc<T>> {
+        let weight = (self.weigh)(&store);
+        let store = Arc::new(store);
+
+        let mut entries = self.lock()?;
+        if entries.generation != since.0 {
+            log::trace!(
+                "not caching an entry loaded before the {name} cache was invalidated",
+                name = self.name
+            );
+            return Ok(store);
+        }
+        entries.remove(&key);
+        if weight > self.capacity {
+            log::debug!(
+                "not caching {weight} in the {name} cache of capacity {capacity}",
+                name = self.name,
+                capacity = self.capacity
+            );
+            return Ok(store);
+        }
+
+        while entries.weight + weight > self.capacity {
+            let Some((_, oldest)) = entries.recency.pop_first() else {
+                break;
+            };
+            if let Some(entry) = entries.map.remove(&oldest) {
+                entries.weight -= entry.weight;
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let last_used = entries.tick();
+        entries.recency.insert(last_used, key.clone());
+        entries.weight += weight;
+        entries.map.insert(
+            key,
+            Entry {
+                store: Arc::clone(&store),
+                creation: Instant::now(),
+                last_used,
+                weight,
+            },
+        );
+
+        Ok(store)
+    }
+
+    /// Invalidate an entry, returning it if it was cached
+    pub fn remove(&self, key: &K) -> anyhow::Result<Option<Arc<T>>> {
+        let mut entries = self.lock()?;
+        entries.generation += 1;
+        Ok(entries.remove(key).map(|entry| entry.store))
+    }
+
+    /// Invalidate every entry matching a predicate, returning how many were dropped
+    pub fn remove_where(&self, predicate: impl Fn(&K, &T) -> bool) -> anyhow::Result<usize> {
+        let mut entries = self.lock()?;
+        entries.generation += 1;
+        let keys = entries
+            .map
+            .iter()
+            .filter(|(key, entry)| predicate(key, &entry.store))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            entries.remove(key);
+        }
+
+        Ok(keys.len())
+    }
+
+    /// Drop entries that outlived the time to live, returning how many were dropped
+    pub fn sweep(&self) -> anyhow::Result<usize> {
+        let mut entries = self.lock()?;
+        let expired = entries
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.creation.elapsed() > self.ttl)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &expired {
+            entries.remove(key);
+        }
+        self.expirations
+            .fetch_add(expired.len() as _, Ordering::Relaxed);
+
+        Ok(expired.len())
+    }
+
+    pub fn stats(&self) -> anyhow::Result<CacheStats> {
+        let entries = self.lock()?;
+        Ok(CacheStats {
+            len: entries.map.len(),
+            weight: entries.weight,
+            capacity: self.capacity,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    /// Insert an entry loaded just now
+    fn insert<T>(cache: &Cache<u32, T>, key: u32, store: T) {
+        cache
+            .insert(key, store, cache.generation().unwrap())
+            .unwrap();
+    }
+
+    fn cached(cache: &Cache<u32, &'static str>, key: u32) -> Option<&'static str> {
+        cache.lookup(&key).unwrap().map(|store| *store)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = Cache::new("test", 3, TTL);
+        insert(&cache, 1, "one");
+        insert(&cache, 2, "two");
+        insert(&cache, 3, "three");
+        // Using 1 makes 2 the least recently used
+        assert_eq!(cached(&cache, 1), Some("one"));
+
+        insert(&cache, 4, "four");
+        assert_eq!(cached(&cache, 2), None);
+        assert_eq!(cached(&cache, 1), Some("one"));
+        assert_eq!(cached(&cache, 3), Some("three"));
+        assert_eq!(cached(&cache, 4), Some("four"));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.weight, stats.evictions), (3, 3, 1));
+    }
+
+    #[test]
+    fn replacing_keeps_one_entry() {
+        let cache = Cache::new("test", 2, TTL);
+        insert(&cache, 1, "one");
+        insert(&cache, 1, "uno");
+        insert(&cache, 2, "two");
+
+        assert_eq!(cached(&cache, 1), Some("uno"));
+        assert_eq!(cached(&cache, 2), Some("two"));
+        assert_eq!(cache.stats().unwrap().evictions, 0);
+    }
+
+    #[test]
+    fn spends_capacity_by_weight() {
+        let cache = Cache::new("test", 10, TTL).with_weigher(|store: &Vec<u8>| store.len());
+        insert(&cache, 1, vec![0; 4]);
+        insert(&cache, 2, vec![0; 4]);
+        assert_eq!(cache.stats().unwrap().weight, 8);
+
+        // Making room for 5 takes evicting the oldest entry
+        insert(&cache, 3, vec![0; 5]);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.weight, stats.evictions), (2, 9, 1));
+        assert!(cache.lookup(&1).unwrap().is_none());
+
+        // And making room for 7 takes evicting both others
+        insert(&cache, 4, vec![0; 7]);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.weight, stats.evictions), (1, 7, 3));
+        assert!(cache.lookup(&4).unwrap().is_some());
+    }
+
+    #[test]
+    fn does_not_keep_oversized_entries() {
+        let cache = Cache::new("test", 10, TTL).with_weigher(|store: &Vec<u8>| store.len());
+        insert(&cache, 1, vec![0; 4]);
+
+        let store = cache
+            .insert(2, vec![0; 11], cache.generation().unwrap())
+            .unwrap();
+        assert_eq!(store.len(), 11);
+        assert!(cache.lookup(&2).unwrap().is_none());
+        // Nothing was evicted for it
+        assert!(cache.lookup(&1).unwrap().is_some());
+        assert_eq!(cache.stats().unwrap().evictions, 0);
+    }
+
+    #[test]
+    fn expires_on_lookup() {
+        let cache = Cache::new("test", 10, Duration::from_millis(20));
+        insert(&cache, 1, "one");
+        assert_eq!(cached(&cache, 1), Some("one"));
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(cached(&cache, 1), None);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.weight, stats.expirations), (0, 0, 1));
+    }
+
+    #[test]
+    fn expires_on_sweep() {
+        let cache = Cache::new("test", 10, Duration::from_millis(20));
+        insert(&cache, 1, "one");
+        insert(&cache, 2, "two");
+        assert_eq!(cache.sweep().unwrap(), 0);
+
+        std::thread::sleep(Duration::from_millis(40));
+        insert(&cache, 3, "three");
+        assert_eq!(cache.sweep().unwrap(), 2);
+        assert_eq!(cached(&cache, 3), Some("three"));
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.expirations), (1, 2));
+    }
+
+    #[test]
+    fn removes_entries() {
+        let cache = Cache::new("test", 10, TTL);
+        for key in 1..=4 {
+            insert(&cache, key, "carta");
+        }
+
+        assert_eq!(cache.remove(&1).unwrap().as_deref(), Some(&"carta"));
+        assert!(cache.remove(&1).unwrap().is_none());
+        assert_eq!(cache.remove_where(|&key, _| key % 2 == 0).unwrap(), 2);
+        assert_eq!(cached(&cache, 3), Some("carta"));
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.len, stats.weight), (1, 1));
+    }
+
+    #[test]
+    fn refuses_entries_loaded_before_invalidation() {
+        let cache = Cache::new("test", 10, TTL);
+        let since = cache.generation().unwrap();
+        cache.remove(&1).unwrap();
+        let store = cache.insert(1, "stale", since).unwrap();
+        assert_eq!(*store, "stale");
+        assert_eq!(cached(&cache, 1), None);
+
+        let since = cache.generation().unwrap();
+        cache.remove_where(|_, _| false).unwrap();
+        cache.insert(1, "stale", since).unwrap();
+        assert_eq!(cached(&cache, 1), None);
+
+        insert(&cache, 1, "fresh");
+        assert_eq!(cached(&cache, 1), Some("fresh"));
+    }
+
+    #[test]
+    fn inserting_does_not_invalidate() {
+        let cache = Cache::new("test", 10, TTL);
+        let since = cache.generation().unwrap();
+        cache.insert(1, "one", since).unwrap();
+        cache.insert(2, "two", since).unwrap();
+        assert_eq!(cached(&cache, 1), Some("one"));
+        assert_eq!(cached(&cache, 2), Some("two"));
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = Cache::new("test", 10, TTL);
+        assert_eq!(cached(&cache, 1), None);
+        insert(&cache, 1, "one");
+        assert_eq!(cached(&cache, 1), Some("one"));
+        assert_eq!(cached(&cache, 1), Some("one"));
+        assert_eq!(cached(&cache, 2), None);
+
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+    }
+}
@@ -1,13 +1,13 @@
-use crate::{database::DATABASE, i18n::Lang};
+use crate::{database::Database, i18n::Lang};
 
-use anyhow::{anyhow, Context as _};
+use anyhow::Context as _;
 use twinstar::{document::HeadingLevel, Document};
 use windmark::context::RouteContext;
 
 /// Delete cartas page UI
-pub fn handle_deleting_cartas(
+pub async fn handle_deleting_cartas(
     context: RouteContext,
-    lang: &Lang,
+    lang: &'static Lang,
 ) -> anyhow::Result<windmark::response::Response> {
     let state = context.parameters.get("state").map(String::as_str);
 
@@ -28,10 +28,11 @@ pub fn handle_deleting_cartas(
             return Ok(windmark::response::Response::temporary_redirect("failure"));
         }
 
-        let mut database_guard = DATABASE
-            .lock()
-            .map_err(|_| anyhow!("failed to lock database mutex"))?;
-        let carta = database_guard.redact_carta(id as _, pin, &lang.deleted)?;
+        let pin = pin.to_string();
+        let carta = Database::run(move |connection| {
+            Database::redact_carta(connection, id as _, &pin, &lang.deleted)
+        })
+        .await?;
 
         return Ok(windmark::response::Response::temporary_redirect(
             match carta {
@@ -1,33 +1,119 @@
-use crate::{abyss::CartaInformation, state::ClientState};
+use crate::{
+    abyss::CartaInformation, consts::PEEK_PAGE_SIZE, database::Database, state::ClientState,
+};
 
 use twinstar::{document::HeadingLevel, Document};
 
-use super::view_carta::display_field;
+use super::view_carta::{display_field, display_replies, fetch_carta_cached};
 
 /// Fetch cartas page UI
-pub fn handle_fetching_cartas(client: &mut ClientState) -> anyhow::Result<String> {
+pub async fn handle_fetching_cartas(client: &mut ClientState) -> anyhow::Result<String> {
     let mut document = Document::new();
     document
         .add_heading(HeadingLevel::H1, &client.lang.abyss_header)
         .add_blank_line()
         .add_link("peek", &client.lang.fetch_link)
-        .add_link("write", &client.lang.write_link)
-        .add_blank_line();
+        .add_link("write", &client.lang.write_link);
+    if client.certificate {
+        let user_id = client.id() as _;
+        let unread =
+            Database::run(move |connection| Database::count_unread_replies(connection, user_id))
+                .await?;
+        document.add_link(
+            "mailbox",
+            format!(
+                "{link} ({unread} {unread_text})",
+                link = client.lang.mailbox_link,
+                unread_text = client.lang.mailbox_unread_text
+            ),
+        );
+    }
+    document.add_blank_line();
+
+    let history = &mut client.abyss_state.top_level_cartas_loaded;
+    let num_unpinned = history.iter().filter(|info| !info.pinned).count();
+    let num_pages = num_unpinned.div_ceil(PEEK_PAGE_SIZE).max(1);
+    let page = client.abyss_state.fetch_page.min(num_pages - 1);
+
+    // Cartas shown may have been redacted or replied to since they were peeked at, so
+    // refresh them from the cache, which is kept up to date
+    let shown = page * PEEK_PAGE_SIZE..(page + 1) * PEEK_PAGE_SIZE;
+    let mut unpinned_idx = 0;
+    for info in history.iter_mut() {
+        let is_shown = info.pinned || {
+            unpinned_idx += 1;
+            shown.contains(&(unpinned_idx - 1))
+        };
+        if is_shown {
+            info.carta = fetch_carta_cached(info.carta.uuid).await?;
+        }
+    }
+
+    // Pinned cartas are always shown above the page of history
+    let (pinned, unpinned): (Vec<_>, Vec<_>) = client
+        .abyss_state
+        .top_level_cartas_loaded
+        .iter()
+        .partition(|info| info.pinned);
 
     document.add_heading(HeadingLevel::H3, "===");
-    for CartaInformation { carta, .. } in &client.abyss_state.top_level_cartas_loaded {
+    for CartaInformation { carta, pinned, .. } in pinned.into_iter().chain(
+        unpinned
+            .into_iter()
+            .skip(page * PEEK_PAGE_SIZE)
+            .take(PEEK_PAGE_SIZE),
+    ) {
         document.add_link(
             format!("read-{uuid}", uuid = carta.uuid).as_str(),
             format!(
-                "{from} - {title}",
+                "{from} - {title}{replies}{pinned}",
                 from = display_field(&carta.sender, &client.lang.from_sentinel),
-                title = display_field(&carta.title, &client.lang.untitled_sentinel)
+                title = display_field(&carta.title, &client.lang.untitled_sentinel),
+                replies = display_replies(carta, client.lang),
+                pinned = if *pinned {
+                    format!(" {}", client.lang.pinned_marker)
+                } else {
+                    String::new()
+                },
             ),
         );
+        document
+            .add_link(
+                format!("pin-{uuid}", uuid = carta.uuid).as_str(),
+                if !pinned {
+                    &client.lang.pin_link
+                } else {
+                    &client.lang.unpin_link
+                },
+            )
+            .add_link(
+                format!("dismiss-{uuid}", uuid = carta.uuid).as_str(),
+                &client.lang.dismiss_link,
+            );
+    }
+    document.add_heading(HeadingLevel::H3, "===");
+    if num_pages > 1 {
+        document.add_text(format!(
+            "{page_text} {page}/{num_pages}",
+            page_text = client.lang.page_text,
+            page = page + 1
+        ));
+        if page > 0 {
+            document.add_link(
+                format!("page-{page}").as_str(),
+                &client.lang.newer_page_link,
+            );
+        }
+        if page + 1 < num_pages {
+            document.add_link(
+                format!("page-{next}", next = page + 2).as_str(),
+                &client.lang.older_page_link,
+            );
+        }
     }
     document
-        .add_heading(HeadingLevel::H3, "===")
         .add_blank_line()
+        .add_link("forget", &client.lang.forget_seen_link)
         .add_link(
             "..",
             if client.certificate {
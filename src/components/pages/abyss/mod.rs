@@ -3,4 +3,6 @@ pub mod fetch_cartas;
 pub mod submit_carta;
 pub mod view_carta;
 pub mod view_cartas;
+pub mod view_mailbox;
+pub mod view_thread;
 pub mod write_carta;
@@ -1,10 +1,11 @@
+use super::view_carta::fetch_carta_cached;
 use crate::{
-    database::{DatabaseCache, DATABASE, DATABASE_CACHE},
+    database::{Database, Visibility},
     state::ClientState,
 };
 
-use anyhow::anyhow;
 use twinstar::{document::HeadingLevel, Document};
+use uuid::Uuid;
 use windmark::context::RouteContext;
 
 pub fn handle_submit_confirmation(
@@ -20,10 +21,10 @@ pub fn handle_submit_confirmation(
     ))
 }
 
-pub fn handle_submit_new(
+pub async fn handle_submit_new(
     client: &mut ClientState,
     context: &RouteContext,
-    reply_uuid: Option<String>,
+    reply_uuid: Option<Uuid>,
 ) -> anyhow::Result<windmark::response::Response> {
     // Ensure carta isn't blank!!
     if client.abyss_state.write_state.lines.is_empty() {
@@ -35,32 +36,36 @@ pub fn handle_submit_new(
     }
 
     let mut parent = None;
+    let mut private_parent = false;
     if let Some(reply_uuid) = reply_uuid {
-        let reply_carta = DatabaseCache::get_or_else(&DATABASE_CACHE.carta, &reply_uuid, &|| {
-            let mut database_guard = DATABASE
-                .lock()
-                .map_err(|_| anyhow!("failed to lock database mutex"))?;
-            database_guard.fetch_carta_uuid(&reply_uuid)
-        })?;
+        let reply_carta = fetch_carta_cached(reply_uuid).await?;
         parent = Some(reply_carta.id);
+        private_parent = reply_carta.visibility == Visibility::Private as i16;
     }
 
-    let mut database_guard = DATABASE
-        .lock()
-        .map_err(|_| anyhow!("failed to lock the database"))?;
-
-    let carta = database_guard.insert_carta(
-        Some(client.id() as _),
-        parent,
-        std::mem::take(&mut client.abyss_state.write_state.lines).join("\n"),
-        std::mem::take(&mut client.abyss_state.write_state.title),
-        std::mem::take(&mut client.abyss_state.write_state.from),
-        client.lang,
-        context
-            .peer_address
-            .map(|ip| ip.ip().to_string())
-            .unwrap_or("0.0.0.0".to_string()),
-    )?;
+    let user_id = Some(client.id() as _);
+    let content = std::mem::take(&mut client.abyss_state.write_state.lines).join("\n");
+    let title = std::mem::take(&mut client.abyss_state.write_state.title);
+    let from = std::mem::take(&mut client.abyss_state.write_state.from);
+    let lang = client.lang;
+    let ip = context
+        .peer_address
+        .map(|ip| ip.ip().to_string())
+        .unwrap_or("0.0.0.0".to_string());
+    let private = std::mem::take(&mut client.abyss_state.write_state.private);
+    // Replies to a private conversation stay in it
+    let visibility = if private_parent || parent.is_some() && private {
+        Visibility::Private
+    } else {
+        Visibility::Public
+    };
+    let lifetime = std::mem::take(&mut client.abyss_state.write_state.lifetime).duration();
+    let carta = Database::run(move |connection| {
+        Database::insert_carta(
+            connection, user_id, parent, content, title, from, lang, ip, visibility, lifetime,
+        )
+    })
+    .await?;
 
     Ok(windmark::response::Response::success(
         Document::new()
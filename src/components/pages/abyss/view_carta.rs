@@ -1,56 +1,140 @@
-use std::{
-    cell::RefCell,
-    rc::Rc,
-    time::{Duration, UNIX_EPOCH},
-};
+use std::{cell::RefCell, sync::Arc};
 
 use crate::{
-    database::{Carta, DatabaseCache, DATABASE, DATABASE_CACHE},
+    components::pages::carta::permalink_available,
+    consts::{THREAD_MAX_DEPTH, THREAD_MAX_SIBLINGS},
+    database::{Carta, Database, DatabaseCache, Visibility, DATABASE_CACHE},
+    i18n::Lang,
     state::ClientState,
-    tree::TreeBranch,
+    tree::{NodeId, Tree},
 };
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 use chrono::{DateTime, Utc};
 use fix_fn::fix_fn;
 use twinstar::{document::HeadingLevel, Document};
+use uuid::Uuid;
 
 pub fn display_field<'a>(field: &'a Option<String>, sentinel: &'a str) -> &'a str {
     field.as_deref().unwrap_or(sentinel).trim_end()
 }
-pub fn display_unix_timestamp(timestamp: u32) -> String {
-    let timestamp = UNIX_EPOCH + Duration::from_secs(timestamp as _);
-    let datetime = DateTime::<Utc>::from(timestamp);
-    datetime.format("%Y-%m-%d %H:%M:%S GMT").to_string()
+/// Marker appended to a carta's listing if it's a private reply
+pub fn display_private(carta: &Carta, marker: &str) -> String {
+    if carta.visibility == Visibility::Private as i16 {
+        format!(" {marker}")
+    } else {
+        String::new()
+    }
+}
+/// Reply count and time of the latest reply appended to a carta's listing
+pub fn display_replies(carta: &Carta, lang: &Lang) -> String {
+    match carta.last_reply {
+        Some(last_reply) if carta.replies > 0 => format!(
+            " ({replies} {replies_text}, {last_reply_text} {time})",
+            replies = carta.replies,
+            replies_text = lang.replies_text,
+            last_reply_text = lang.last_reply_text,
+            time = display_timestamp(last_reply),
+        ),
+        _ => String::new(),
+    }
+}
+pub fn display_timestamp(timestamp: DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S GMT").to_string()
+}
+
+/// Private replies are only shown to the replier and the author they replied to
+pub async fn visible_to_client(carta: &Carta, client: &ClientState) -> anyhow::Result<bool> {
+    if carta.visibility != Visibility::Private as i16 {
+        return Ok(true);
+    }
+    let parent_author = match carta.parent {
+        Some(parent) => {
+            Database::run(move |connection| Database::fetch_carta(connection, parent))
+                .await?
+                .user_id
+        }
+        None => None,
+    };
+    Ok(carta.visible_to(parent_author, Some(client.id() as _)))
+}
+/// Fetch the whole thread a carta is part of, from the cache if possible
+pub async fn fetch_thread(carta: &Carta) -> anyhow::Result<Arc<Tree<Carta>>> {
+    // Only a miss on a reply's root needs a connection
+    let cached_root = match carta.parent {
+        Some(_) => DATABASE_CACHE
+            .root
+            .lookup(&carta.id)?
+            .map(|root_id| *root_id),
+        None => Some(carta.id),
+    };
+    let root_id = match cached_root {
+        Some(root_id) => root_id,
+        None => {
+            let carta = carta.clone();
+            Database::run(move |connection| Database::thread_root(connection, &carta)).await?
+        }
+    };
+
+    DatabaseCache::get_or_else(&DATABASE_CACHE.thread, &root_id, || {
+        Database::run(move |connection| {
+            Database::fetch_carta_tree(connection, root_id).context("fetching carta tree")
+        })
+    })
+    .await
+}
+/// Fetch a carta from its UUID, from the cache if possible
+pub async fn fetch_carta_cached(uuid: Uuid) -> anyhow::Result<Arc<Carta>> {
+    DatabaseCache::get_or_else(&DATABASE_CACHE.carta, &uuid, || {
+        Database::run(move |connection| Database::fetch_carta_uuid(connection, uuid))
+    })
+    .await
+}
+
+/// Helper function to add a link to a carta in a reply tree
+fn add_reply_link(
+    document: &mut Document,
+    carta: &Carta,
+    indent: usize,
+    current: bool,
+    lang: &Lang,
+) {
+    document.add_link(
+        format!("read-{uuid}", uuid = carta.uuid).as_str(),
+        format!(
+            "{indent}{from} - {title}{private}",
+            indent = if !current { "- " } else { "+ " }.repeat(indent),
+            from = display_field(&carta.sender, &lang.from_sentinel),
+            title = display_field(&carta.title, &lang.untitled_sentinel),
+            private = display_private(carta, &lang.private_marker),
+        ),
+    );
 }
 
 /// Fetch cartas page UI
-pub fn handle_viewing_carta(client: &mut ClientState, uuid: String) -> anyhow::Result<String> {
+pub async fn handle_viewing_carta(client: &mut ClientState, uuid: Uuid) -> anyhow::Result<String> {
     let mut document = Document::new();
 
     document
         .add_heading(HeadingLevel::H1, &client.lang.view_header)
         .add_blank_line();
 
-    let carta = DatabaseCache::get_or_else(&DATABASE_CACHE.carta, &uuid, &|| {
-        let mut database_guard = DATABASE
-            .lock()
-            .map_err(|_| anyhow!("failed to lock database mutex"))?;
-        database_guard.fetch_carta_uuid(&uuid)
-    })?;
-    let mut database_guard = DATABASE
-        .lock()
-        .map_err(|_| anyhow!("failed to lock database mutex"))?;
-    let carta_tree = database_guard
-        .fetch_carta_tree(carta.id)
-        .context("fetching carta tree")?;
+    let carta = fetch_carta_cached(uuid).await?;
+    if !visible_to_client(&carta, client).await? {
+        document
+            .add_text(&client.lang.view_private_text)
+            .add_blank_line()
+            .add_link("fetch", &client.lang.return_link);
+        return Ok(document.to_string());
+    }
+    let carta_tree = fetch_thread(&carta).await?;
 
     // Display carta
     document.add_heading(
         HeadingLevel::H3,
         format!(
             "{time} / {from} - {title}",
-            time = display_unix_timestamp(carta.modification.unwrap_or(carta.creation) as _),
+            time = display_timestamp(carta.modification.unwrap_or(carta.creation)),
             from = display_field(&carta.sender, &client.lang.from_sentinel),
             title = display_field(&carta.title, &client.lang.untitled_sentinel)
         ),
@@ -58,33 +142,103 @@ pub fn handle_viewing_carta(client: &mut ClientState, uuid: String) -> anyhow::R
     for line in carta.content.split('\n') {
         document.add_preformatted(line);
     }
+    if let Some(expiration) = carta.expiration {
+        document.add_text(format!(
+            "{text} {time}",
+            text = client.lang.view_expiration_text,
+            time = display_timestamp(expiration)
+        ));
+    }
     document.add_heading(HeadingLevel::H3, "===");
 
-    // Display reply tree
+    // Display reply tree: the carta's ancestors, then its replies down to
+    // `THREAD_MAX_DEPTH` levels with at most `THREAD_MAX_SIBLINGS` replies each
     document
         .add_blank_line()
         .add_heading(HeadingLevel::H3, &client.lang.view_replies_header);
+    let current_node = carta_tree
+        .find(|node| node.uuid == uuid)
+        .context("carta not found in its own tree")?;
+    let viewer = Some(client.id() as _);
+    let ancestors = carta_tree.ancestors(current_node).collect::<Vec<_>>();
+    for (idx, &ancestor) in ancestors.iter().rev().enumerate() {
+        let parent_author = carta_tree
+            .parent(ancestor)
+            .and_then(|parent| carta_tree[parent].user_id);
+        if !carta_tree[ancestor].visible_to(parent_author, viewer) {
+            continue;
+        }
+        add_reply_link(
+            &mut document,
+            &carta_tree[ancestor],
+            idx + 1,
+            false,
+            client.lang,
+        );
+    }
+    let expanded = client.abyss_state.expanded_carta == Some(uuid);
     let document_ref = RefCell::new(document);
     #[allow(clippy::unused_unit)] // fix_fn needs a return type
     let reply_tree = fix_fn!(
-        |reply_tree, indent: usize, tree: Rc<TreeBranch<Carta>>| -> () {
-            let current = tree.node.uuid == uuid;
-            document_ref.borrow_mut().add_link(
-                format!("read-{uuid}", uuid = &tree.node.uuid).as_str(),
-                format!(
-                    "{indent}{from} - {title}",
-                    indent = if !current { "- " } else { "+ " }.repeat(indent),
-                    from = display_field(&tree.node.sender, &client.lang.from_sentinel),
-                    title = display_field(&tree.node.title, &client.lang.untitled_sentinel)
-                ),
+        |reply_tree, indent: usize, depth: usize, node: NodeId| -> () {
+            let carta = &carta_tree[node];
+            add_reply_link(
+                &mut document_ref.borrow_mut(),
+                carta,
+                indent,
+                carta.uuid == uuid,
+                client.lang,
             );
-            for child in tree.children.borrow().iter() {
-                reply_tree(indent + 1, Rc::clone(child))
+
+            let children = carta_tree
+                .children(node)
+                .iter()
+                .copied()
+                .filter(|&child| carta_tree[child].visible_to(carta.user_id, viewer))
+                .collect::<Vec<_>>();
+            if children.is_empty() {
+                return;
+            }
+            // Too deep; link to re-root the view at this carta instead
+            if depth >= *THREAD_MAX_DEPTH {
+                document_ref.borrow_mut().add_link(
+                    format!("read-{uuid}", uuid = carta.uuid).as_str(),
+                    format!(
+                        "{indent}{text} ({count})",
+                        indent = "- ".repeat(indent + 1),
+                        text = client.lang.continue_thread_link,
+                        count = children.len(),
+                    ),
+                );
+                return;
+            }
+            let max_siblings = if depth == 0 && expanded {
+                children.len()
+            } else {
+                *THREAD_MAX_SIBLINGS
+            };
+            for &child in children.iter().take(max_siblings) {
+                reply_tree(indent + 1, depth + 1, child)
+            }
+            if children.len() > max_siblings {
+                document_ref.borrow_mut().add_link(
+                    format!("expand-{uuid}", uuid = carta.uuid).as_str(),
+                    format!(
+                        "{indent}{count} {text}",
+                        indent = "- ".repeat(indent + 1),
+                        count = children.len() - max_siblings,
+                        text = client.lang.more_replies_link,
+                    ),
+                );
             }
         }
     );
-    reply_tree(1, Rc::new(carta_tree));
+    reply_tree(ancestors.len() + 1, 0, current_node);
     let mut document = document_ref.into_inner();
+    document.add_link(
+        format!("thread-{uuid}").as_str(),
+        &client.lang.view_thread_link,
+    );
     document.add_heading(HeadingLevel::H3, "===");
 
     if carta
@@ -95,13 +249,38 @@ pub fn handle_viewing_carta(client: &mut ClientState, uuid: String) -> anyhow::R
             .add_blank_line()
             .add_text(format!(
                 "{text} {pin}{id}",
-                text = &client.lang.delete_code_text,
+                text = client.lang.delete_code_text,
                 pin = carta.modification_code,
                 id = carta.id
             ))
             .add_link("../delete", &client.lang.abyss_delete_link);
     }
 
+    if let Some(carta_info) = client.abyss_state.loaded_mut(uuid) {
+        let pinned = carta_info.pinned;
+        document
+            .add_blank_line()
+            .add_link(
+                format!("pin-{uuid}").as_str(),
+                if !pinned {
+                    &client.lang.pin_link
+                } else {
+                    &client.lang.unpin_link
+                },
+            )
+            .add_link(
+                format!("dismiss-{uuid}").as_str(),
+                &client.lang.dismiss_link,
+            );
+    }
+
+    if permalink_available(&carta_tree, current_node) {
+        document.add_blank_line().add_link(
+            format!("/{code}/carta/{uuid}", code = client.lang.code).as_str(),
+            &client.lang.permalink_link,
+        );
+    }
+
     document
         .add_blank_line()
         .add_link(
@@ -1,30 +1,78 @@
-use super::view_carta::{display_field, display_unix_timestamp};
-use crate::{database::DATABASE, state::ClientState};
+use super::view_carta::{display_field, display_replies, display_timestamp};
+use crate::{
+    consts::VIEW_CARTAS_PAGE_SIZE,
+    database::{CartaKind, CartaSort, Database},
+    i18n::Lang,
+    state::ClientState,
+};
 
-use anyhow::anyhow;
 use twinstar::{document::HeadingLevel, Document};
 
+fn display_sort(sort: CartaSort, lang: &Lang) -> &str {
+    match sort {
+        CartaSort::Newest => &lang.sort_newest,
+        CartaSort::Oldest => &lang.sort_oldest,
+        CartaSort::MostReplies => &lang.sort_most_replies,
+        CartaSort::MostReported => &lang.sort_most_reported,
+    }
+}
+fn display_kind(kind: CartaKind, lang: &Lang) -> &str {
+    match kind {
+        CartaKind::All => &lang.kind_all,
+        CartaKind::TopLevel => &lang.kind_top_level,
+        CartaKind::Replies => &lang.kind_replies,
+    }
+}
+
 /// Handle viewing cartas
-pub fn handle_viewing_cartas(client: &mut ClientState) -> anyhow::Result<String> {
+pub async fn handle_viewing_cartas(client: &mut ClientState) -> anyhow::Result<String> {
+    let view_state = &client.abyss_state.view_state;
+
     let mut document = Document::new();
     document
         .add_heading(HeadingLevel::H1, &client.lang.all_header)
         .add_blank_line()
+        .add_link(
+            "view-sort",
+            format!(
+                "{sort_text}: {sort}",
+                sort_text = client.lang.view_sort_link,
+                sort = display_sort(view_state.sort, client.lang),
+            ),
+        )
+        .add_link(
+            "view-kind",
+            format!(
+                "{kind_text}: {kind}",
+                kind_text = client.lang.view_kind_link,
+                kind = display_kind(view_state.filter.kind, client.lang),
+            ),
+        )
+        .add_blank_line()
         .add_heading(HeadingLevel::H3, "===");
 
-    let mut database_guard = DATABASE
-        .lock()
-        .map_err(|_| anyhow!("failed to lock database mutex"))?;
-    let cartas = database_guard.fetch_cartas(client.id() as _)?;
+    let (user_id, sort, filter, page) = (
+        client.id() as _,
+        view_state.sort,
+        view_state.filter,
+        view_state.page,
+    );
+    let mut cartas = Database::run(move |connection| {
+        Database::fetch_cartas(connection, user_id, sort, filter, page)
+    })
+    .await?;
+    let has_next_page = cartas.len() > VIEW_CARTAS_PAGE_SIZE;
+    cartas.truncate(VIEW_CARTAS_PAGE_SIZE);
 
     for carta in &cartas {
         document.add_link(
             format!("read-{uuid}", uuid = carta.uuid).as_str(),
             format!(
-                "{time} / {from} - {title}",
-                time = display_unix_timestamp(carta.creation as _),
+                "{time} / {from} - {title}{replies}",
+                time = display_timestamp(carta.creation),
                 from = display_field(&carta.sender, &client.lang.from_sentinel),
                 title = display_field(&carta.title, &client.lang.untitled_sentinel),
+                replies = display_replies(carta, client.lang),
             ),
         );
     }
@@ -32,10 +80,28 @@ pub fn handle_viewing_cartas(client: &mut ClientState) -> anyhow::Result<String>
         document.add_text(&client.lang.all_empty_text);
     }
 
-    document
-        .add_heading(HeadingLevel::H3, "===")
-        .add_blank_line()
-        .add_link("fetch", "<--");
+    document.add_heading(HeadingLevel::H3, "===");
+    if view_state.page > 0 || has_next_page {
+        document.add_text(format!(
+            "{page_text} {page}",
+            page_text = client.lang.page_text,
+            page = view_state.page + 1
+        ));
+        if view_state.page > 0 {
+            document.add_link(
+                format!("view-page-{page}", page = view_state.page).as_str(),
+                &client.lang.newer_page_link,
+            );
+        }
+        if has_next_page {
+            document.add_link(
+                format!("view-page-{next}", next = view_state.page + 2).as_str(),
+                &client.lang.older_page_link,
+            );
+        }
+    }
+
+    document.add_blank_line().add_link("fetch", "<--");
 
     Ok(document.to_string())
 }
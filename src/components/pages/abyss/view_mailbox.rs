@@ -0,0 +1,62 @@
+use super::view_carta::{display_field, display_private, display_timestamp};
+use crate::{components::pages::feed::feed_token, database::Database, state::ClientState};
+
+use anyhow::Context as _;
+use twinstar::{document::HeadingLevel, Document};
+
+/// Handle viewing replies to the client's cartas
+pub async fn handle_viewing_mailbox(client: &mut ClientState) -> anyhow::Result<String> {
+    let mut document = Document::new();
+    document
+        .add_heading(HeadingLevel::H1, &client.lang.mailbox_header)
+        .add_blank_line()
+        .add_heading(HeadingLevel::H3, "===");
+
+    let user_id = client.id() as _;
+    let (last_read, replies, user) = Database::run(move |connection| {
+        Ok((
+            Database::fetch_last_read(connection, user_id)?,
+            Database::fetch_replies(connection, user_id)?,
+            Database::fetch_user_id(connection, user_id)?.context("client has no user")?,
+        ))
+    })
+    .await?;
+    let token = feed_token(&user)?;
+
+    for carta in &replies {
+        document.add_link(
+            format!("read-{uuid}", uuid = carta.uuid).as_str(),
+            format!(
+                "{time} / {from} - {title}{private}{new}",
+                time = display_timestamp(carta.creation),
+                from = display_field(&carta.sender, &client.lang.from_sentinel),
+                title = display_field(&carta.title, &client.lang.untitled_sentinel),
+                private = display_private(carta, &client.lang.private_marker),
+                new = if carta.creation > last_read {
+                    format!(" {}", client.lang.mailbox_new_marker)
+                } else {
+                    String::new()
+                },
+            ),
+        );
+    }
+    if replies.is_empty() {
+        document.add_text(&client.lang.mailbox_empty_text);
+    }
+
+    document
+        .add_heading(HeadingLevel::H3, "===")
+        .add_blank_line()
+        .add_link("mark-read", &client.lang.mailbox_mark_read_link)
+        .add_link(
+            format!("/{code}/feed/{token}", code = client.lang.code).as_str(),
+            &client.lang.mailbox_feed_link,
+        )
+        .add_link(
+            format!("/{code}/feed/{token}/atom", code = client.lang.code).as_str(),
+            &client.lang.mailbox_atom_feed_link,
+        )
+        .add_link("fetch", "<--");
+
+    Ok(document.to_string())
+}
@@ -0,0 +1,107 @@
+use super::view_carta::{
+    display_field, display_private, display_timestamp, fetch_carta_cached, fetch_thread,
+    visible_to_client,
+};
+use crate::{
+    abyss::ThreadOrder,
+    database::Carta,
+    state::ClientState,
+    tree::{NodeId, Tree},
+};
+
+use twinstar::{document::HeadingLevel, Document};
+use uuid::Uuid;
+
+/// Every carta in a thread the viewer can read, in tree order along with its depth.
+/// Replies below a hidden private reply are left out too.
+pub fn visible_thread(tree: &Tree<Carta>, viewer: Option<i32>) -> Vec<(NodeId, usize)> {
+    let mut visible = vec![];
+    let mut pending = vec![(tree.root(), 0)];
+    while let Some((node, depth)) = pending.pop() {
+        visible.push((node, depth));
+        for &child in tree.children(node).iter().rev() {
+            if tree[child].visible_to(tree[node].user_id, viewer) {
+                pending.push((child, depth + 1));
+            }
+        }
+    }
+    visible
+}
+
+/// Handle viewing every carta of a thread on a single page
+pub async fn handle_viewing_thread(client: &mut ClientState, uuid: Uuid) -> anyhow::Result<String> {
+    let mut document = Document::new();
+
+    document
+        .add_heading(HeadingLevel::H1, &client.lang.thread_header)
+        .add_blank_line();
+
+    let carta = fetch_carta_cached(uuid).await?;
+    if !visible_to_client(&carta, client).await? {
+        document
+            .add_text(&client.lang.view_private_text)
+            .add_blank_line()
+            .add_link("fetch", &client.lang.return_link);
+        return Ok(document.to_string());
+    }
+    let carta_tree = fetch_thread(&carta).await?;
+
+    let order = client.abyss_state.thread_order;
+    document
+        .add_link(
+            "thread-order",
+            format!(
+                "{text}: {order}",
+                text = client.lang.thread_order_link,
+                order = order.display(client.lang)
+            ),
+        )
+        .add_blank_line();
+
+    let mut thread = visible_thread(&carta_tree, Some(client.id() as _));
+    if let ThreadOrder::Chronological = order {
+        thread.sort_by_key(|&(node, _)| (carta_tree[node].creation, carta_tree[node].id));
+    }
+
+    for (node, depth) in thread {
+        let thread_carta = &carta_tree[node];
+        let indent = match order {
+            ThreadOrder::Tree => depth,
+            ThreadOrder::Chronological => 0,
+        };
+        document.add_heading(
+            HeadingLevel::H3,
+            format!(
+                "{indent}{time} / {from} - {title}{private}",
+                indent = if thread_carta.uuid != uuid {
+                    "- "
+                } else {
+                    "+ "
+                }
+                .repeat(indent),
+                time =
+                    display_timestamp(thread_carta.modification.unwrap_or(thread_carta.creation)),
+                from = display_field(&thread_carta.sender, &client.lang.from_sentinel),
+                title = display_field(&thread_carta.title, &client.lang.untitled_sentinel),
+                private = display_private(thread_carta, &client.lang.private_marker),
+            ),
+        );
+        for line in thread_carta.content.split('\n') {
+            document.add_preformatted(line);
+        }
+        document
+            .add_link(
+                format!("read-{uuid}", uuid = thread_carta.uuid).as_str(),
+                &client.lang.thread_read_link,
+            )
+            .add_blank_line();
+    }
+
+    document
+        .add_heading(HeadingLevel::H3, "===")
+        .add_blank_line()
+        .add_link(format!("read-{uuid}").as_str(), "<--")
+        .add_link("fetch", &client.lang.return_link);
+
+    Ok(document.to_string())
+}
@@ -2,6 +2,7 @@ use crate::{consts::MAX_NUM_LINES, state::ClientState};
 
 use lazy_static::lazy_static;
 use twinstar::{document::HeadingLevel, Document};
+use uuid::Uuid;
 
 use super::view_carta::display_field;
 
@@ -16,7 +17,7 @@ lazy_static! {
 // Write carta page UI
 pub fn handle_writing_carta(
     client: &mut ClientState,
-    reply_uuid: Option<String>,
+    reply_uuid: Option<Uuid>,
 ) -> anyhow::Result<String> {
     let mut document = Document::new();
     document
@@ -42,7 +43,7 @@ pub fn handle_writing_carta(
                 {
                     format!(
                         "{line_number_formatted} {line}",
-                        line = &client.abyss_state.write_state.lines[idx]
+                        line = client.abyss_state.write_state.lines[idx]
                     )
                 }
                 _new_line => format!(
@@ -60,7 +61,7 @@ pub fn handle_writing_carta(
         "title",
         format!(
             "{title_text}: {title}",
-            title_text = &client.lang.write_title_link,
+            title_text = client.lang.write_title_link,
             title = display_field(
                 &client.abyss_state.write_state.title,
                 &client.lang.untitled_sentinel
@@ -71,13 +72,21 @@ pub fn handle_writing_carta(
         "from",
         format!(
             "{from_text}: {from}",
-            from_text = &client.lang.write_from_link,
+            from_text = client.lang.write_from_link,
             from = display_field(
                 &client.abyss_state.write_state.from,
                 &client.lang.from_sentinel
             ),
         ),
     );
+    document.add_link(
+        "lifetime",
+        format!(
+            "{lifetime_text}: {lifetime}",
+            lifetime_text = client.lang.write_lifetime_link,
+            lifetime = client.abyss_state.write_state.lifetime.display(client.lang),
+        ),
+    );
     document.add_heading(HeadingLevel::H3, "===");
     document
         .add_blank_line()
@@ -91,6 +100,16 @@ pub fn handle_writing_carta(
             &client.lang.write_show_line_numbers_link
         },
     );
+    if reply_uuid.is_some() {
+        document.add_link(
+            "toggle-private",
+            if !client.abyss_state.write_state.private {
+                &client.lang.write_private_link
+            } else {
+                &client.lang.write_public_link
+            },
+        );
+    }
     document.add_blank_line();
     if let Some(reply_uuid) = reply_uuid {
         document.add_link(format!("read-{reply_uuid}").as_str(), "<--");
@@ -0,0 +1,131 @@
+use super::abyss::{
+    view_carta::{display_field, display_timestamp, fetch_carta_cached, fetch_thread},
+    view_thread::visible_thread,
+};
+use crate::{
+    consts::PERMALINK_MAX_REPORTS,
+    database::{is_not_found, Carta, Visibility},
+    i18n::Lang,
+    tree::{NodeId, Tree},
+};
+
+use anyhow::Context as _;
+use twinstar::{document::HeadingLevel, Document};
+use uuid::Uuid;
+use windmark::context::RouteContext;
+
+/// Whether a carta can be shown to anyone through its permalink. Replies under a
+/// private reply are part of a private conversation, so they aren't either.
+fn publicly_visible(tree: &Tree<Carta>, node: NodeId) -> bool {
+    let carta = &tree[node];
+    carta.visibility == Visibility::Public as i16
+        && carta.reports < *PERMALINK_MAX_REPORTS
+        && tree
+            .ancestors(node)
+            .all(|ancestor| tree[ancestor].visibility == Visibility::Public as i16)
+}
+
+/// Whether a carta's permalink leads to it. Threads taken out of the abyss by
+/// deletion or moderation aren't shared.
+pub fn permalink_available(tree: &Tree<Carta>, node: NodeId) -> bool {
+    publicly_visible(tree, node) && tree[tree.root()].random_accessible
+}
+
+/// Stateless page for a carta and its thread, readable without entering the abyss
+pub async fn carta(
+    context: RouteContext,
+    lang: &'static Lang,
+) -> anyhow::Result<windmark::response::Response> {
+    let Some(uuid) = context
+        .parameters
+        .get("uuid")
+        .and_then(|uuid| uuid.parse::<Uuid>().ok())
+    else {
+        return Ok(windmark::response::Response::not_found(
+            &lang.permalink_unavailable_text,
+        ));
+    };
+
+    let mut document = Document::new();
+    document
+        .add_heading(HeadingLevel::H1, &lang.view_header)
+        .add_blank_line();
+
+    let carta = match fetch_carta_cached(uuid).await {
+        Ok(carta) => carta,
+        Err(e) if is_not_found(&e) => {
+            return Ok(windmark::response::Response::not_found(
+                &lang.permalink_unavailable_text,
+            ))
+        }
+        Err(e) => return Err(e),
+    };
+    let carta_tree = fetch_thread(&carta).await?;
+    let current_node = carta_tree
+        .find(|node| node.uuid == uuid)
+        .context("carta not found in its own tree")?;
+
+    if !permalink_available(&carta_tree, current_node) {
+        document
+            .add_text(&lang.permalink_unavailable_text)
+            .add_blank_line()
+            .add_link(format!("/{code}/", code = lang.code).as_str(), "<--");
+        return Ok(windmark::response::Response::success(document.to_string()));
+    }
+
+    // Display carta
+    document.add_heading(
+        HeadingLevel::H3,
+        format!(
+            "{time} / {from} - {title}",
+            time = display_timestamp(carta.modification.unwrap_or(carta.creation)),
+            from = display_field(&carta.sender, &lang.from_sentinel),
+            title = display_field(&carta.title, &lang.untitled_sentinel)
+        ),
+    );
+    for line in carta.content.split('\n') {
+        document.add_preformatted(line);
+    }
+    document.add_heading(HeadingLevel::H3, "===");
+
+    // Display the public part of the thread, linking to other permalinks
+    document
+        .add_blank_line()
+        .add_heading(HeadingLevel::H3, &lang.view_replies_header);
+    for (node, depth) in visible_thread(&carta_tree, None) {
+        let thread_carta = &carta_tree[node];
+        if !publicly_visible(&carta_tree, node) {
+            continue;
+        }
+        document.add_link(
+            format!(
+                "/{code}/carta/{uuid}",
+                code = lang.code,
+                uuid = thread_carta.uuid
+            )
+            .as_str(),
+            format!(
+                "{indent}{from} - {title}",
+                indent = if thread_carta.uuid != uuid {
+                    "- "
+                } else {
+                    "+ "
+                }
+                .repeat(depth + 1),
+                from = display_field(&thread_carta.sender, &lang.from_sentinel),
+                title = display_field(&thread_carta.title, &lang.untitled_sentinel),
+            ),
+        );
+    }
+    document.add_heading(HeadingLevel::H3, "===");
+
+    document
+        .add_blank_line()
+        .add_link(
+            format!("/{code}/abyss/reply-{uuid}", code = lang.code).as_str(),
+            &lang.view_add_reply_link,
+        )
+        .add_link(format!("/{code}/", code = lang.code).as_str(), "<--");
+
+    Ok(windmark::response::Response::success(document.to_string()))
+}
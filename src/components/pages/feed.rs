@@ -0,0 +1,159 @@
+use super::abyss::view_carta::{display_field, display_private};
+use crate::{
+    consts::FEED_SECRET,
+    database::{Carta, Database, User},
+    i18n::Lang,
+};
+
+use anyhow::Context as _;
+use chrono::DateTime;
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
+use twinstar::{document::HeadingLevel, Document};
+use windmark::context::RouteContext;
+
+/// Unguessable token for a user's private feed, `<user id>-<hex HMAC of their
+/// certificate hash>`. It stays the same for as long as the user record does.
+pub fn feed_token(user: &User) -> anyhow::Result<String> {
+    let key = PKey::hmac(FEED_SECRET.as_bytes()).context("creating feed token key")?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(&user.id.to_be_bytes())?;
+    signer.update(&user.certificate_hash)?;
+    let mac = signer.sign_to_vec().context("signing feed token")?;
+
+    let hex = mac
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<String>();
+    Ok(format!("{id}-{hex}", id = user.id))
+}
+
+/// Fetch the replies to the user a feed token belongs to, if the token is valid
+async fn fetch_feed_replies(context: &RouteContext) -> anyhow::Result<Option<Vec<Carta>>> {
+    let token = context.parameters.get("token").context("no feed token")?;
+    let Some(id) = token
+        .split_once('-')
+        .and_then(|(id, _)| id.parse::<i32>().ok())
+    else {
+        return Ok(None);
+    };
+
+    let token = token.clone();
+    Database::run(move |connection| {
+        let Some(user) = Database::fetch_user_id(connection, id)? else {
+            return Ok(None);
+        };
+        let expected = feed_token(&user)?;
+        if expected.len() != token.len() || !memcmp::eq(expected.as_bytes(), token.as_bytes()) {
+            return Ok(None);
+        }
+
+        Ok(Some(Database::fetch_replies(connection, user.id)?))
+    })
+    .await
+}
+
+/// Escape text for XML element content and attribute values
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `/:lang/feed/:token`, replies to a user's cartas as a gemfeed
+pub async fn feed(
+    context: RouteContext,
+    lang: &'static Lang,
+) -> anyhow::Result<windmark::response::Response> {
+    let Some(replies) = fetch_feed_replies(&context).await? else {
+        return Ok(windmark::response::Response::not_found(
+            &lang.feed_invalid_text,
+        ));
+    };
+
+    let mut document = Document::new();
+    document
+        .add_heading(HeadingLevel::H1, &lang.feed_header)
+        .add_heading(HeadingLevel::H2, &lang.feed_subheader)
+        .add_blank_line();
+    for carta in &replies {
+        document.add_link(
+            format!(
+                "/{code}/abyss/read-{uuid}",
+                code = lang.code,
+                uuid = carta.uuid
+            )
+            .as_str(),
+            format!(
+                "{date} {from} - {title}{private}",
+                date = carta.creation.format("%Y-%m-%d"),
+                from = display_field(&carta.sender, &lang.from_sentinel),
+                title = display_field(&carta.title, &lang.untitled_sentinel),
+                private = display_private(carta, &lang.private_marker),
+            ),
+        );
+    }
+
+    Ok(windmark::response::Response::success(document.to_string()))
+}
+
+/// `/:lang/feed/:token/atom`, replies to a user's cartas as an Atom feed
+pub async fn atom_feed(
+    context: RouteContext,
+    lang: &'static Lang,
+) -> anyhow::Result<windmark::response::Response> {
+    let Some(replies) = fetch_feed_replies(&context).await? else {
+        return Ok(windmark::response::Response::not_found(
+            &lang.feed_invalid_text,
+        ));
+    };
+
+    // Atom needs absolute links
+    let mut base = context.url.clone();
+    base.set_query(None);
+    base.set_path("");
+    let base = base.as_str().trim_end_matches('/');
+
+    let updated = replies
+        .first()
+        .map(|carta| carta.creation)
+        .unwrap_or(DateTime::UNIX_EPOCH);
+    let mut atom = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+        <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+        <title>{title}</title>\n\
+        <subtitle>{subtitle}</subtitle>\n\
+        <id>{self_link}</id>\n\
+        <link rel=\"self\" href=\"{self_link}\"/>\n\
+        <updated>{updated}</updated>\n",
+        title = escape_xml(&lang.feed_header),
+        subtitle = escape_xml(&lang.feed_subheader),
+        self_link = escape_xml(&format!("{base}{path}", path = context.url.path())),
+        updated = updated.to_rfc3339(),
+    );
+    for carta in &replies {
+        atom.push_str(&format!(
+            "<entry>\n\
+            <title>{title}{private}</title>\n\
+            <id>urn:uuid:{uuid}</id>\n\
+            <link href=\"{base}/{code}/abyss/read-{uuid}\"/>\n\
+            <updated>{updated}</updated>\n\
+            <author><name>{from}</name></author>\n\
+            <content type=\"text\">{content}</content>\n\
+            </entry>\n",
+            title = escape_xml(display_field(&carta.title, &lang.untitled_sentinel)),
+            private = escape_xml(&display_private(carta, &lang.private_marker)),
+            uuid = carta.uuid,
+            code = lang.code,
+            updated = carta.modification.unwrap_or(carta.creation).to_rfc3339(),
+            from = escape_xml(display_field(&carta.sender, &lang.from_sentinel)),
+            content = escape_xml(&carta.content),
+        ));
+    }
+    atom.push_str("</feed>\n");
+
+    Ok(windmark::response::Response::new(20, atom)
+        .with_mime("application/atom+xml")
+        .clone())
+}
@@ -1,4 +1,6 @@
 pub mod abyss;
+pub mod carta;
 pub mod certless;
+pub mod feed;
 pub mod index;
 pub mod terms;
@@ -1,18 +1,57 @@
 //! Lazily loaded constants and regular constants.
 
 use lazy_static::lazy_static;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
 
-use crate::database::{Carta, DATABASE};
+use crate::database::{Carta, Database, SelectionStrategy};
 
 macro_rules! from_environment {
     ($key:expr) => {
         std::env::var($key).expect(&format!("not set environment variable: {}", $key))
     };
+    ($key:expr, $default:expr) => {
+        std::env::var($key)
+            .map(|value| {
+                value
+                    .parse()
+                    .expect(&format!("invalid environment variable: {}", $key))
+            })
+            .unwrap_or($default)
+    };
 }
 
 lazy_static! {
     pub static ref DATABASE_URL: String = from_environment!("DATABASE_URL");
+    pub static ref DATABASE_POOL_SIZE: u32 = from_environment!("DATABASE_POOL_SIZE", 10);
+    pub static ref DATABASE_CONNECT_TIMEOUT_SECS: u64 =
+        from_environment!("DATABASE_CONNECT_TIMEOUT_SECS", 5);
+    pub static ref DATABASE_IDLE_TIMEOUT_SECS: u64 =
+        from_environment!("DATABASE_IDLE_TIMEOUT_SECS", 600);
+    pub static ref DATABASE_STATEMENT_TIMEOUT_MS: u64 =
+        from_environment!("DATABASE_STATEMENT_TIMEOUT_MS", 5000);
+    pub static ref DATABASE_TIMEOUT_SECS: u64 = from_environment!("DATABASE_TIMEOUT_SECS", 10);
+    pub static ref CACHE_TTL_SECS: u64 = from_environment!("CACHE_TTL_SECS", 3600);
+    pub static ref CACHE_MAX_USERS: usize = from_environment!("CACHE_MAX_USERS", 10000);
+    pub static ref CACHE_MAX_CARTAS: usize = from_environment!("CACHE_MAX_CARTAS", 10000);
+    pub static ref CACHE_MAX_THREAD_CARTAS: usize =
+        from_environment!("CACHE_MAX_THREAD_CARTAS", 50000);
+    /// Key feed links are signed with. Anyone knowing it can read every user's feed.
+    pub static ref FEED_SECRET: String = {
+        let secret: String = from_environment!("FEED_SECRET");
+        assert!(
+            !secret.is_empty() && secret != "change-me",
+            "FEED_SECRET must be set to a secret of your own"
+        );
+        secret
+    };
+    pub static ref CARTA_SELECTION: SelectionStrategy =
+        from_environment!("CARTA_SELECTION", SelectionStrategy::default());
+    pub static ref THREAD_MAX_DEPTH: usize = from_environment!("THREAD_MAX_DEPTH", 5);
+    pub static ref THREAD_MAX_SIBLINGS: usize = from_environment!("THREAD_MAX_SIBLINGS", 10);
+    pub static ref PERMALINK_MAX_REPORTS: i32 = from_environment!("PERMALINK_MAX_REPORTS", 3);
     pub static ref I18N_DIR: PathBuf = std::env::current_dir()
         .unwrap()
         .join("i18n")
@@ -20,11 +59,34 @@ lazy_static! {
         .expect("i18n directory not found");
 }
 
-lazy_static! {
-    pub static ref DEFAULT_CARTA: Arc<Carta> = {
-        let mut database_guard = DATABASE.lock().unwrap();
-        Arc::new(database_guard.fetch_carta(0).unwrap())
-    };
+/// Load every setting from the environment up front, so a missing or invalid one
+/// stops startup instead of panicking inside a request handler
+pub fn ensure_lazily_loaded_constants_work() {
+    lazy_static::initialize(&DATABASE_URL);
+    lazy_static::initialize(&DATABASE_POOL_SIZE);
+    lazy_static::initialize(&DATABASE_CONNECT_TIMEOUT_SECS);
+    lazy_static::initialize(&DATABASE_IDLE_TIMEOUT_SECS);
+    lazy_static::initialize(&DATABASE_STATEMENT_TIMEOUT_MS);
+    lazy_static::initialize(&DATABASE_TIMEOUT_SECS);
+    lazy_static::initialize(&CACHE_TTL_SECS);
+    lazy_static::initialize(&CACHE_MAX_USERS);
+    lazy_static::initialize(&CACHE_MAX_CARTAS);
+    lazy_static::initialize(&CACHE_MAX_THREAD_CARTAS);
+    lazy_static::initialize(&CARTA_SELECTION);
+    lazy_static::initialize(&THREAD_MAX_DEPTH);
+    lazy_static::initialize(&THREAD_MAX_SIBLINGS);
+    lazy_static::initialize(&PERMALINK_MAX_REPORTS);
+    lazy_static::initialize(&I18N_DIR);
+}
+
+static DEFAULT_CARTA: OnceLock<Arc<Carta>> = OnceLock::new();
+/// The carta every client starts with, loaded from the database on first use
+pub async fn default_carta() -> anyhow::Result<Arc<Carta>> {
+    if let Some(carta) = DEFAULT_CARTA.get() {
+        return Ok(Arc::clone(carta));
+    }
+    let carta = Database::run(|connection| Database::fetch_carta(connection, 0)).await?;
+    Ok(Arc::clone(DEFAULT_CARTA.get_or_init(|| Arc::new(carta))))
 }
 
 pub const MAX_LINE_LEN: usize = 2048;
@@ -32,5 +94,15 @@ pub const MAX_NUM_LINES: usize = 50;
 pub const MAX_TITLE_LEN: usize = 32; // must match database!
 pub const MAX_FROM_LEN: usize = 24; // must match database!
 pub const PERIODIC_PRUNE_SECS: usize = 600; // 10 minutes
+pub const PERIODIC_EXPIRE_SECS: usize = 60; // 1 minute
+pub const PERIODIC_CACHE_SWEEP_SECS: usize = 60; // 1 minute
+pub const MAX_MAILBOX_LEN: i64 = 50;
+pub const MAX_PEEK_HISTORY: usize = 100;
+pub const PEEK_PAGE_SIZE: usize = 10;
+pub const VIEW_CARTAS_PAGE_SIZE: usize = 20;
+pub const RANDOM_CARTA_SAMPLE_SIZE: i64 = 32;
+pub const DATABASE_CONNECT_RETRIES: u32 = 3;
+pub const DATABASE_RETRY_BACKOFF_MILLIS: u64 = 250;
+pub const STARTUP_MAX_BACKOFF_SECS: u64 = 30;
 
 pub const FOOTER: &str = "sheepy.moe <3";
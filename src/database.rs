@@ -1,231 +1,715 @@
 //! ORM types for the database
 
+use crate::backend::{Backend, ConnectionSetup, DbConnection, SqlUuid, FIRST_BIND, MIGRATIONS};
+use crate::cache::{Cache, CacheKey};
 use crate::components::certificate::CERT_HASH_LEN;
-use crate::tree::TreeBranch;
-use crate::{consts::DATABASE_URL, i18n::Lang};
+use crate::tree::Tree;
+use crate::{
+    consts::{
+        CACHE_MAX_CARTAS, CACHE_MAX_THREAD_CARTAS, CACHE_MAX_USERS, CACHE_TTL_SECS,
+        DATABASE_CONNECT_RETRIES, DATABASE_CONNECT_TIMEOUT_SECS, DATABASE_IDLE_TIMEOUT_SECS,
+        DATABASE_POOL_SIZE, DATABASE_RETRY_BACKOFF_MILLIS, DATABASE_TIMEOUT_SECS, DATABASE_URL,
+        MAX_MAILBOX_LEN, RANDOM_CARTA_SAMPLE_SIZE, VIEW_CARTAS_PAGE_SIZE,
+    },
+    i18n::{Lang, ENGLISH},
+};
 
 use anyhow::{anyhow, Context as _};
+use chrono::{DateTime, Utc};
 use diesel::{
+    dsl::{exists, not},
+    migration::MigrationSource,
     prelude::*,
     r2d2::{ConnectionManager, Pool, PooledConnection},
+    sql_types::Integer,
+    upsert::excluded,
 };
-use fix_fn::fix_fn;
+use diesel_migrations::MigrationHarness;
 use lazy_static::lazy_static;
 use rand::distributions::Uniform;
 use rand::prelude::Distribution as _;
-use rand::thread_rng;
+use rand::seq::{IteratorRandom as _, SliceRandom as _};
+use rand::{thread_rng, Rng as _};
 use serde::Serialize;
 use std::collections::HashMap;
-use std::hash::Hash;
-use std::sync::MutexGuard;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::{
-    cell::RefCell,
-    rc::Rc,
-    sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-pub type PgPool = Pool<ConnectionManager<PgConnection>>;
-pub type PooledPg = PooledConnection<ConnectionManager<PgConnection>>;
+use tokio::{task::spawn_blocking, time::timeout};
+use uuid::Uuid;
 
-pub const CACHE_INVALIDATION_SECS: u64 = 3600; // 1 hour
+pub type DbPool = Pool<ConnectionManager<DbConnection>>;
+pub type PooledDb = PooledConnection<ConnectionManager<DbConnection>>;
 
 lazy_static! {
-    pub static ref DATABASE: Arc<Mutex<Database>> = Arc::new(Mutex::new(Database::new(
-        establish_connection()
-            .and_then(|pool| pool.get().context("getting pool connection"))
-            .expect("establish database connection")
-    )));
-    pub static ref DATABASE_CACHE: DatabaseCache = Default::default();
+    pub static ref DATABASE_POOL: DbPool = establish_connection();
+    pub static ref DATABASE_CACHE: DatabaseCache = DatabaseCache::new();
 }
 
 /// A database cache to avoid storing heap-allocated objects for every user
-#[derive(Default)]
 pub struct DatabaseCache {
-    pub user: Arc<Mutex<HashMap<[u8; CERT_HASH_LEN], Cache<User>>>>,
-    pub carta: Arc<Mutex<HashMap<String, Cache<Carta>>>>,
+    pub user: Cache<[u8; CERT_HASH_LEN], User>,
+    pub carta: Cache<Uuid, Carta>,
+    /// Whole threads, keyed by the ID of their top-level carta and weighed by how
+    /// many cartas they hold
+    pub thread: Cache<i32, Tree<Carta>>,
+    /// The ID of the top-level carta of a reply's thread, keyed by the reply's ID.
+    /// Replies never move between threads, so this never goes stale.
+    pub root: Cache<i32, i32>,
 }
-pub struct Cache<T> {
-    pub creation: Instant,
-    pub store: Arc<T>,
-}
-pub trait CacheKey: Hash + Eq + Clone {}
-impl<K> CacheKey for K where K: Hash + Eq + Clone {}
 impl DatabaseCache {
-    pub type TCache<K: Hash + Eq, T> = Arc<Mutex<HashMap<K, Cache<T>>>>;
+    pub fn new() -> Self {
+        let ttl = Duration::from_secs(*CACHE_TTL_SECS);
+        Self {
+            user: Cache::new("user", *CACHE_MAX_USERS, ttl),
+            carta: Cache::new("carta", *CACHE_MAX_CARTAS, ttl),
+            thread: Cache::new("thread", *CACHE_MAX_THREAD_CARTAS, ttl).with_weigher(Tree::len),
+            root: Cache::new("root", *CACHE_MAX_CARTAS, ttl),
+        }
+    }
 
-    pub fn lookup_cache<K: CacheKey, T>(
-        cache: &Self::TCache<K, T>,
+    pub async fn get_or_else<K: CacheKey, T, F>(
+        cache: &Cache<K, T>,
         key: &K,
-    ) -> anyhow::Result<Option<Arc<T>>> {
-        let mut guard: MutexGuard<HashMap<_, Cache<T>>> = cache
-            .lock()
-            .map_err(|_| anyhow!("failed to lock db cache mutex"))?;
-
-        let mut remove = false;
-        let mut store = None;
-        if let Some(cache) = guard.get(key) {
-            if Instant::now().duration_since(cache.creation)
-                > Duration::from_secs(CACHE_INVALIDATION_SECS)
-            {
-                remove = true;
-            } else {
-                store = Some(Arc::clone(&cache.store));
-            }
-        }
-        if remove {
-            guard.remove(key);
-            log::trace!("not found");
-            return Ok(None);
+        otherwise: impl FnOnce() -> F,
+    ) -> anyhow::Result<Arc<T>>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        if let Some(t) = cache.lookup(key)? {
+            return Ok(t);
         }
-
-        Ok(store)
+        let since = cache.generation()?;
+        cache.insert(key.clone(), otherwise().await?, since)
     }
 
-    pub fn insert_cache<K: CacheKey, T>(
-        cache: &Self::TCache<K, T>,
-        key: &K,
-        store: T,
-    ) -> anyhow::Result<Arc<T>> {
-        let mut guard: MutexGuard<HashMap<_, Cache<T>>> = cache
-            .lock()
-            .map_err(|_| anyhow!("failed to lock db cache mutex"))?;
-
-        let cache = Cache {
-            creation: Instant::now(),
-            store: Arc::new(store),
-        };
+    /// Drop a carta that changed in the database, and the cached thread holding it.
+    /// Call this once the change is committed, so it's loaded fresh next time.
+    pub fn carta_changed(&self, carta: &Carta, root_id: i32) -> anyhow::Result<()> {
+        self.thread_changed(root_id)?;
+        self.carta.remove(&carta.uuid)?;
+        Ok(())
+    }
 
-        let store = Arc::clone(&cache.store);
-        guard.insert(key.clone(), cache);
+    /// Drop a cached thread from the ID of its top-level carta, such as when it's
+    /// replied to
+    pub fn thread_changed(&self, root_id: i32) -> anyhow::Result<()> {
+        self.thread.remove(&root_id)?;
+        log::trace!("dropped cached thread with root id {root_id}");
+        Ok(())
+    }
 
-        Ok(store)
+    /// Drop a user that changed in the database
+    pub fn user_changed(&self, id: i32) -> anyhow::Result<()> {
+        self.user.remove_where(|_, user| user.id == id)?;
+        Ok(())
     }
 
-    pub fn get_or_else<K: CacheKey, T>(
-        cache: &Self::TCache<K, T>,
-        key: &K,
-        otherwise: &dyn Fn() -> anyhow::Result<T>,
-    ) -> anyhow::Result<Arc<T>> {
-        if let Some(t) = Self::lookup_cache(cache, key)? {
-            return Ok(t);
+    /// Drop expired entries from every cache, logging how each cache is doing
+    pub fn sweep(&self) -> anyhow::Result<()> {
+        fn sweep_one<K: CacheKey, T>(cache: &Cache<K, T>) -> anyhow::Result<()> {
+            let expired = cache.sweep()?;
+            let stats = cache.stats()?;
+            log::debug!(
+                "{name} cache: {len} entries weighing {weight}/{capacity}, {hits} hits, \
+                {misses} misses, {evictions} evicted, {expired} expired this sweep",
+                name = cache.name(),
+                len = stats.len,
+                weight = stats.weight,
+                capacity = stats.capacity,
+                hits = stats.hits,
+                misses = stats.misses,
+                evictions = stats.evictions,
+            );
+            Ok(())
         }
-        Self::insert_cache(cache, key, otherwise()?)
+
+        sweep_one(&self.user)?;
+        sweep_one(&self.carta)?;
+        sweep_one(&self.thread)?;
+        sweep_one(&self.root)
+    }
+}
+impl Default for DatabaseCache {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-/// Establish a pool and database connection from `DATABASE_URL`
-pub fn establish_connection() -> anyhow::Result<PgPool> {
-    log::trace!("initializing database connection");
+/// Redact expired cartas
+pub async fn prune_expired_cartas() -> anyhow::Result<()> {
+    let expired =
+        Database::run(|connection| Database::expire_cartas(connection, &ENGLISH.expired)).await?;
 
-    let manager = ConnectionManager::<PgConnection>::new(DATABASE_URL.to_string());
+    for carta in expired {
+        log::debug!("expired carta with id {id}", id = carta.id);
+    }
 
-    let pool = PgPool::builder()
-        .max_size(10)
-        .build(manager)
-        .context("creating postgresql pool and ocnnection manager")?;
+    Ok(())
+}
 
-    Ok(pool)
+/// The database can't be reached right now. Responded to with `41 SERVER UNAVAILABLE`.
+#[derive(Debug)]
+pub struct DatabaseUnavailable;
+impl std::fmt::Display for DatabaseUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "database unavailable")
+    }
 }
+impl std::error::Error for DatabaseUnavailable {}
 
-#[derive(Queryable, Selectable, Serialize, Clone, Debug)]
+/// Whether an error comes from a row that doesn't exist
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<diesel::result::Error>(),
+            Some(diesel::result::Error::NotFound)
+        )
+    })
+}
+
+/// Establish a connection pool from `DATABASE_URL`. Connections are opened lazily,
+/// so this doesn't fail if the database isn't up yet.
+pub fn establish_connection() -> DbPool {
+    log::trace!("initializing database connection pool");
+
+    let manager = ConnectionManager::<DbConnection>::new(DATABASE_URL.to_string());
+
+    DbPool::builder()
+        .max_size(*DATABASE_POOL_SIZE)
+        .min_idle(Some(0))
+        .connection_timeout(Duration::from_secs(*DATABASE_CONNECT_TIMEOUT_SECS))
+        .idle_timeout(Some(Duration::from_secs(*DATABASE_IDLE_TIMEOUT_SECS)))
+        .connection_customizer(Box::new(ConnectionSetup))
+        .build_unchecked(manager)
+}
+
+/// Bring the schema up to date with the bundled migrations, only listing the pending
+/// ones in a dry run. Refuses to touch a database migrated by a newer version.
+///
+/// This opens its own connection rather than a pooled one, so migrations aren't cut
+/// short by the statement timeout.
+pub fn run_migrations(dry_run: bool) -> anyhow::Result<()> {
+    let mut connection = DbConnection::establish(&DATABASE_URL)
+        .map_err(|e| anyhow!(e).context(DatabaseUnavailable))
+        .context("connecting to the database to migrate it")?;
+
+    let bundled = MigrationSource::<Backend>::migrations(&MIGRATIONS)
+        .map_err(|e| anyhow!(e))
+        .context("loading bundled migrations")?
+        .iter()
+        .map(|migration| migration.name().version().as_owned())
+        .collect::<Vec<_>>();
+    let applied = connection
+        .applied_migrations()
+        .map_err(|e| anyhow!(e))
+        .context("fetching applied migrations")?;
+    if let Some(unknown) = applied.iter().find(|version| !bundled.contains(version)) {
+        Err(anyhow!(
+            "the database has migration {unknown} applied, which this version doesn't know \
+            of. refusing to run on a newer schema"
+        ))?;
+    }
+
+    let pending = connection
+        .pending_migrations(MIGRATIONS)
+        .map_err(|e| anyhow!(e))
+        .context("fetching pending migrations")?;
+    if pending.is_empty() {
+        log::info!("database schema is up to date");
+        return Ok(());
+    }
+    for migration in &pending {
+        log::info!(
+            "{action} migration {name}",
+            action = if dry_run { "pending" } else { "applying" },
+            name = migration.name()
+        );
+    }
+    if !dry_run {
+        connection
+            .run_migrations(&pending)
+            .map_err(|e| anyhow!(e))
+            .context("running migrations")?;
+        log::info!("applied {count} migrations", count = pending.len());
+    }
+
+    Ok(())
+}
+
+#[derive(Queryable, QueryableByName, Selectable, Serialize, Clone, Debug)]
 #[diesel(table_name = crate::schema::cartas)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::backend::Backend))]
 pub struct Carta {
     pub id: i32,
-    pub uuid: String, // 32-len + 4 hyphens
+    #[diesel(deserialize_as = SqlUuid)]
+    pub uuid: Uuid,
     pub parent: Option<i32>,
     pub user_id: Option<i32>,
     pub title: Option<String>,     // max len: 24
     pub sender: Option<String>,    // max len: 12
     pub content: String,           // max len: 2048
     pub modification_code: String, // 6-digit pin
-    pub creation: i32,             // unix timestamp
-    pub modification: Option<i32>, // unix timestamp
-    pub lang: String,              // 2-digit code, e.g. `en`
+    pub creation: DateTime<Utc>,
+    pub modification: Option<DateTime<Utc>>,
+    pub lang: String, // 2-digit code, e.g. `en`
     pub random_accessible: bool,
     pub reports: i32,
     pub ip: String,
+    pub visibility: i16,                   // see [`Visibility`]
+    pub expiration: Option<DateTime<Utc>>, // null never expires
+    pub views: i32,
+    pub replies: i32, // public replies only
+    pub last_reply: Option<DateTime<Utc>>,
+}
+impl Carta {
+    /// Whether a viewer can read this carta, given the author of its parent
+    pub fn visible_to(&self, parent_author: Option<i32>, viewer: Option<i32>) -> bool {
+        if self.visibility != Visibility::Private as i16 {
+            return true;
+        }
+        viewer.is_some_and(|viewer| self.user_id == Some(viewer) || parent_author == Some(viewer))
+    }
 }
 #[derive(Insertable, Serialize, Clone, Debug)]
 #[diesel(table_name = crate::schema::cartas)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::backend::Backend))]
 pub struct CartaUpdate {
-    pub uuid: String, // 32-len + 4 hyphens
+    #[diesel(serialize_as = SqlUuid)]
+    pub uuid: Uuid,
     pub parent: Option<i32>,
     pub user_id: Option<i32>,
     pub title: Option<String>,     // max len: 24
     pub sender: Option<String>,    // max len: 12
     pub content: String,           // max len: 2048
     pub modification_code: String, // 6-digit pin
-    pub creation: i32,             // unix timestamp
-    pub modification: Option<i32>, // unix timestamp
-    pub lang: String,              // 2 digit code, e.g. `en`
+    pub creation: DateTime<Utc>,
+    pub modification: Option<DateTime<Utc>>,
+    pub lang: String, // 2 digit code, e.g. `en`
     pub random_accessible: bool,
     pub reports: i32,
     pub ip: String,
+    pub visibility: i16,
+    pub expiration: Option<DateTime<Utc>>, // null never expires
+    pub views: i32,
+    pub replies: i32, // public replies only
+    pub last_reply: Option<DateTime<Utc>>,
+}
+
+/// What deleting or expiring a carta changes, taking it out of the abyss and
+/// forgetting who wrote it
+#[derive(AsChangeset)]
+#[diesel(table_name = crate::schema::cartas)]
+struct Redaction<'a> {
+    random_accessible: bool,
+    #[diesel(treat_none_as_null = true)]
+    user_id: Option<i32>,
+    content: &'a str,
+    title: &'a str,
+    sender: &'a str,
+    modification: DateTime<Utc>,
+}
+impl<'a> Redaction<'a> {
+    fn new(redact_text: &'a str, modification: DateTime<Utc>) -> Self {
+        Self {
+            random_accessible: false,
+            user_id: None,
+            content: redact_text,
+            title: redact_text,
+            sender: redact_text,
+            modification,
+        }
+    }
+}
+
+/// How [`Database::fetch_random_carta`] picks a carta, set with `CARTA_SELECTION`
+#[derive(Clone, Copy, Debug, Default)]
+pub enum SelectionStrategy {
+    /// Any carta, picked from a window sampled at a random id. Cartas right after a
+    /// long run of ids that can't be peeked at, such as replies or cartas in other
+    /// languages, are somewhat more likely.
+    #[default]
+    Random,
+    /// The newest cartas, weighted toward the newest among them
+    Recent,
+    /// Cartas with the fewest replies across the abyss first, at random among ties
+    Unanswered,
+    /// Cartas shown the fewest times across the abyss first, at random among ties
+    LeastShown,
+}
+impl FromStr for SelectionStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "random" => Self::Random,
+            "recent" => Self::Recent,
+            "unanswered" => Self::Unanswered,
+            "least-shown" => Self::LeastShown,
+            _ => Err(anyhow!("unknown carta selection strategy `{s}`"))?,
+        })
+    }
+}
+
+/// Order of the cartas listed by [`Database::fetch_cartas`]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum CartaSort {
+    #[default]
+    Newest,
+    Oldest,
+    MostReplies,
+    MostReported,
+}
+impl CartaSort {
+    /// Cycle to the next sort order
+    pub fn next(self) -> Self {
+        match self {
+            Self::Newest => Self::Oldest,
+            Self::Oldest => Self::MostReplies,
+            Self::MostReplies => Self::MostReported,
+            Self::MostReported => Self::Newest,
+        }
+    }
+}
+/// Which cartas are listed by [`Database::fetch_cartas`]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CartaFilter {
+    pub kind: CartaKind,
+}
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum CartaKind {
+    #[default]
+    All,
+    TopLevel,
+    Replies,
+}
+impl CartaKind {
+    /// Cycle to the next kind of carta
+    pub fn next(self) -> Self {
+        match self {
+            Self::All => Self::TopLevel,
+            Self::TopLevel => Self::Replies,
+            Self::Replies => Self::All,
+        }
+    }
+}
+
+/// Who can read a carta
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(i16)]
+pub enum Visibility {
+    /// Anyone who can read the carta tree
+    Public = 0,
+    /// Only the replier and the author of the carta being replied to
+    Private = 1,
 }
 
 #[derive(Queryable, Selectable, Serialize, Clone, Debug)]
 #[diesel(table_name = crate::schema::users)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::backend::Backend))]
 pub struct User {
     pub id: i32,
     pub certificate_hash: Vec<u8>, // max len: [`crate::certificate::CERT_HASH_LEN`]
     pub lang: String,
-    pub creation: i32, // unix timestamp
+    pub creation: DateTime<Utc>,
 }
 #[derive(Insertable, Serialize, Clone, Debug)]
 #[diesel(table_name = crate::schema::users)]
-#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(check_for_backend(crate::backend::Backend))]
 pub struct UserUpdate {
     pub certificate_hash: Vec<u8>,
     pub lang: String,
-    pub creation: i32,
+    pub creation: DateTime<Utc>,
 }
 
-pub struct Database {
-    pub connection: PooledPg,
+/// Assemble the cartas of a thread into a tree under the carta with `root_id`, with
+/// replies in the order they were written. Cartas whose parent isn't in the thread
+/// are left out.
+fn assemble_carta_tree(root_id: i32, thread: Vec<Carta>) -> anyhow::Result<Tree<Carta>> {
+    // Group cartas by their parent to assemble the tree in memory
+    let mut root = None;
+    let mut children = HashMap::<i32, Vec<Carta>>::new();
+    for carta in thread {
+        match carta.parent {
+            Some(parent) if carta.id != root_id => children.entry(parent).or_default().push(carta),
+            _ => root = Some(carta),
+        }
+    }
+    for siblings in children.values_mut() {
+        siblings.sort_by_key(|carta| carta.id);
+    }
+    let root = root.with_context(|| anyhow!("no carta with id {root_id}"))?;
+
+    let mut tree = Tree::new(root);
+    let mut pending = vec![tree.root()];
+    while let Some(parent) = pending.pop() {
+        for child in children.remove(&tree[parent].id).unwrap_or_default() {
+            pending.push(tree.push(parent, child));
+        }
+    }
+
+    Ok(tree)
 }
+
+/// Database operations, each run on a connection checked out of [`DATABASE_POOL`]
+pub struct Database;
 impl Database {
-    pub fn new(connection: PooledPg) -> Self {
-        Self { connection }
+    /// Check out a connection from the pool for the duration of an operation. The
+    /// pool replaces broken connections itself, so checking out is retried with
+    /// backoff to ride out PostgreSQL restarting, until the deadline passes.
+    pub fn connection(deadline: Instant) -> anyhow::Result<PooledDb> {
+        let connect_timeout = Duration::from_secs(*DATABASE_CONNECT_TIMEOUT_SECS);
+        let mut backoff = Duration::from_millis(DATABASE_RETRY_BACKOFF_MILLIS);
+        for attempt in 1..=DATABASE_CONNECT_RETRIES {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match DATABASE_POOL.get_timeout(remaining.min(connect_timeout)) {
+                Ok(connection) => return Ok(connection),
+                Err(e) => log::warn!(
+                    "checking out a database connection failed \
+                    ({attempt}/{DATABASE_CONNECT_RETRIES}): {e}"
+                ),
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if attempt == DATABASE_CONNECT_RETRIES || remaining <= backoff {
+                break;
+            }
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        Err(DatabaseUnavailable).context("checking out a database connection")
     }
 
-    /// Fetch a random "random accessible" carta
-    pub fn fetch_random_carta<I>(
-        &mut self,
-        languages: &[String],
-        ignore_ids: I,
-    ) -> anyhow::Result<Option<Carta>>
+    /// Run an operation on a pooled connection in tokio's blocking pool, so queries
+    /// never block the async workers
+    ///
+    /// The caller gives up after [`struct@DATABASE_TIMEOUT_SECS`], and so does
+    /// checking out a connection. If the caller stops
+    /// waiting before the operation starts, such as when the client disconnects and
+    /// its handler is dropped, the operation is skipped altogether.
+    pub async fn run<T, F>(operation: F) -> anyhow::Result<T>
     where
-        I: Iterator<Item = i32>,
+        T: Send + 'static,
+        F: FnOnce(&mut DbConnection) -> anyhow::Result<T> + Send + 'static,
     {
-        log::trace!("fetching a random carta from languages {languages:?}");
+        /// Flags the operation as cancelled once the caller stops waiting
+        struct CancelOnDrop(Arc<AtomicBool>);
+        impl Drop for CancelOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel_on_drop = CancelOnDrop(Arc::clone(&cancelled));
+        let timeout_duration = Duration::from_secs(*DATABASE_TIMEOUT_SECS);
+        let deadline = Instant::now() + timeout_duration;
+
+        let operation = spawn_blocking(move || {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(anyhow!("database operation cancelled"));
+            }
+            let mut connection = Self::connection(deadline)?;
+            operation(&mut connection)
+        });
+        timeout(timeout_duration, operation)
+            .await
+            .map_err(|_| DatabaseUnavailable)
+            .context("database operation timed out")?
+            .context("database operation panicked")?
+    }
+
+    /// Fetch a "random accessible" carta the user hasn't seen, picked by a
+    /// [`SelectionStrategy`], counting it as shown and seen
+    ///
+    /// Rather than sorting the whole table, candidates are read off an index: a window
+    /// scanned from a random id for the random strategy, or the newest cartas, or the
+    /// ones with the fewest replies or views otherwise. This stays fast no matter how
+    /// many cartas there are.
+    pub fn fetch_random_carta(
+        connection: &mut DbConnection,
+        languages: &[String],
+        user_id: i32,
+        strategy: SelectionStrategy,
+    ) -> anyhow::Result<Option<Carta>> {
+        log::trace!("fetching a {strategy:?} carta from languages {languages:?}");
+
+        let candidates = match strategy {
+            SelectionStrategy::Random => {
+                Self::sample_random_cartas(connection, languages, user_id)?
+            }
+            SelectionStrategy::Recent
+            | SelectionStrategy::Unanswered
+            | SelectionStrategy::LeastShown => {
+                Self::sample_ordered_cartas(connection, languages, user_id, strategy)?
+            }
+        };
+        let Some(first) = candidates.first() else {
+            return Ok(None);
+        };
+
+        let mut rng = thread_rng();
+        let picked = match strategy {
+            SelectionStrategy::Random => candidates.choose(&mut rng),
+            // Weighted random sampling (Efraimidis-Spirakis) with a weight that halves
+            // for every day a carta has been around
+            SelectionStrategy::Recent => {
+                let now = Utc::now();
+                candidates
+                    .iter()
+                    .map(|carta| {
+                        let age_days = (now - carta.creation).num_seconds().max(0) as f64 / 86400.0;
+                        (rng.gen::<f64>().ln() * 2f64.powf(age_days), carta)
+                    })
+                    .max_by(|(a, _), (b, _)| a.total_cmp(b))
+                    .map(|(_, carta)| carta)
+            }
+            // Candidates are sorted, so the first one has the fewest
+            SelectionStrategy::Unanswered => candidates
+                .iter()
+                .filter(|carta| carta.replies == first.replies)
+                .choose(&mut rng),
+            SelectionStrategy::LeastShown => candidates
+                .iter()
+                .filter(|carta| carta.views == first.views)
+                .choose(&mut rng),
+        }
+        .context("no candidates")?;
+
+        use crate::schema::cartas::dsl;
+        let random_carta = diesel::update(dsl::cartas.find(picked.id))
+            .set(dsl::views.eq(dsl::views + 1))
+            .get_result::<Carta>(connection)
+            .context("counting carta as shown")?;
 
-        diesel::define_sql_function!(fn random() -> Text);
+        use crate::schema::seen_cartas::dsl as seen_dsl;
+        diesel::insert_into(seen_dsl::seen_cartas)
+            .values((
+                seen_dsl::user_id.eq(user_id),
+                seen_dsl::carta_id.eq(random_carta.id),
+            ))
+            .on_conflict_do_nothing()
+            .execute(connection)
+            .context("marking carta as seen")?;
+
+        Ok(Some(random_carta))
+    }
+
+    /// Helper function to sample up to [`RANDOM_CARTA_SAMPLE_SIZE`] unseen "random
+    /// accessible" cartas, scanning upward from a random id and wrapping around to the
+    /// lowest id
+    fn sample_random_cartas(
+        connection: &mut DbConnection,
+        languages: &[String],
+        user_id: i32,
+    ) -> anyhow::Result<Vec<Carta>> {
+        use crate::schema::cartas::dsl;
+        use crate::schema::seen_cartas::dsl as seen_dsl;
+        let max_id = dsl::cartas
+            .select(dsl::id)
+            .order(dsl::id.desc())
+            .first::<i32>(connection)
+            .optional()
+            .context("fetching highest carta id")?;
+        let Some(max_id) = max_id else {
+            return Ok(vec![]);
+        };
+        let pivot = thread_rng().gen_range(0..=max_id);
+
+        let mut candidates = Vec::with_capacity(RANDOM_CARTA_SAMPLE_SIZE as _);
+        for (from, until) in [(pivot, None), (i32::MIN, Some(pivot))] {
+            let mut query = dsl::cartas
+                .filter(dsl::random_accessible)
+                .filter(dsl::lang.eq_any(languages))
+                .filter(dsl::id.ge(from))
+                .filter(not(exists(
+                    seen_dsl::seen_cartas
+                        .filter(seen_dsl::user_id.eq(user_id))
+                        .filter(seen_dsl::carta_id.eq(dsl::id)),
+                )))
+                .select(Carta::as_select())
+                .order(dsl::id)
+                .limit(RANDOM_CARTA_SAMPLE_SIZE - candidates.len() as i64)
+                .into_boxed();
+            if let Some(until) = until {
+                query = query.filter(dsl::id.lt(until));
+            }
+            candidates.extend(query.load(connection).context("sampling random cartas")?);
+            if candidates.len() as i64 >= RANDOM_CARTA_SAMPLE_SIZE {
+                break;
+            }
+        }
+
+        log::trace!(
+            "sampled {count} cartas from pivot {pivot}",
+            count = candidates.len()
+        );
+
+        Ok(candidates)
+    }
 
+    /// Helper function to fetch up to [`RANDOM_CARTA_SAMPLE_SIZE`] unseen "random
+    /// accessible" cartas in the strategy's order: the newest, or the ones with the
+    /// fewest replies or views
+    fn sample_ordered_cartas(
+        connection: &mut DbConnection,
+        languages: &[String],
+        user_id: i32,
+        strategy: SelectionStrategy,
+    ) -> anyhow::Result<Vec<Carta>> {
         use crate::schema::cartas::dsl;
-        let random_carta = dsl::cartas
-            .filter(dsl::random_accessible.eq(true))
-            .filter(dsl::id.ne_all(ignore_ids))
+        use crate::schema::seen_cartas::dsl as seen_dsl;
+        let query = dsl::cartas
+            .filter(dsl::random_accessible)
             .filter(dsl::lang.eq_any(languages))
+            .filter(not(exists(
+                seen_dsl::seen_cartas
+                    .filter(seen_dsl::user_id.eq(user_id))
+                    .filter(seen_dsl::carta_id.eq(dsl::id)),
+            )))
             .select(Carta::as_select())
-            .order(random())
-            .first(&mut self.connection)
-            .optional()?;
+            .limit(RANDOM_CARTA_SAMPLE_SIZE)
+            .into_boxed();
+        let query = match strategy {
+            // Ids are handed out in creation order
+            SelectionStrategy::Recent => query.order(dsl::id.desc()),
+            SelectionStrategy::LeastShown => query.order((dsl::views, dsl::id)),
+            _ => query.order((dsl::replies, dsl::id)),
+        };
+        let candidates = query
+            .load(connection)
+            .with_context(|| anyhow!("sampling {strategy:?} cartas"))?;
+
+        log::trace!(
+            "sampled {count} {strategy:?} cartas",
+            count = candidates.len()
+        );
 
-        Ok(random_carta)
+        Ok(candidates)
+    }
+
+    /// Forget which cartas a user has seen, letting them be peeked at again
+    pub fn forget_seen_cartas(connection: &mut DbConnection, user_id: i32) -> anyhow::Result<()> {
+        use crate::schema::seen_cartas::dsl;
+        let forgotten = diesel::delete(dsl::seen_cartas.filter(dsl::user_id.eq(user_id)))
+            .execute(connection)
+            .context("forgetting seen cartas")?;
+
+        log::trace!("forgot {forgotten} cartas seen by user id {user_id}");
+
+        Ok(())
     }
 
     /// Fetch a user from their identifier
-    pub fn fetch_user(&mut self, identifier: &[u8]) -> anyhow::Result<Option<User>> {
+    pub fn fetch_user(
+        connection: &mut DbConnection,
+        identifier: &[u8],
+    ) -> anyhow::Result<Option<User>> {
         use crate::schema::users::dsl;
         let user = dsl::users
             .filter(dsl::certificate_hash.eq(identifier))
             .select(User::as_select())
-            .first(&mut self.connection)
+            .first(connection)
             .optional()
             .with_context(|| anyhow!("fetching user by cert hash"))?;
 
@@ -242,22 +726,47 @@ impl Database {
         }
     }
 
-    /// Insert a new user
-    pub fn insert_user(&mut self, lang: String, identifier: &[u8]) -> anyhow::Result<User> {
+    /// Fetch a user from their ID
+    pub fn fetch_user_id(connection: &mut DbConnection, id: i32) -> anyhow::Result<Option<User>> {
+        use crate::schema::users::dsl;
+        let user = dsl::users
+            .find(id)
+            .select(User::as_select())
+            .first(connection)
+            .optional()
+            .with_context(|| anyhow!("fetching user with id {id}"))?;
+
+        log::trace!(
+            "fetched user with id {id}: found {found}",
+            found = user.is_some()
+        );
+
+        Ok(user)
+    }
+
+    /// Insert a new user, or return the existing one if their certificate was inserted
+    /// concurrently
+    pub fn insert_user(
+        connection: &mut DbConnection,
+        lang: String,
+        identifier: &[u8],
+    ) -> anyhow::Result<User> {
         let update = UserUpdate {
             certificate_hash: identifier.to_vec(),
-            creation: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as _,
+            creation: Utc::now(),
             lang,
         };
 
         use crate::schema::users::dsl;
+        // Updating the conflicting row to itself makes it returned like an inserted row
         let user = update
             .insert_into(dsl::users)
+            .on_conflict(dsl::certificate_hash)
+            .do_update()
+            .set(dsl::certificate_hash.eq(excluded(dsl::certificate_hash)))
             .returning(User::as_returning())
-            .get_result(&mut self.connection)?;
+            .get_result(connection)
+            .context("upserting user")?;
 
         log::trace!("inserted user {id}", id = user.id);
 
@@ -265,12 +774,17 @@ impl Database {
     }
 
     /// Change the language for a user
-    pub fn change_language(&mut self, id: i32, code: &str) -> anyhow::Result<()> {
+    pub fn change_language(
+        connection: &mut DbConnection,
+        id: i32,
+        code: &str,
+    ) -> anyhow::Result<()> {
         use crate::schema::users::dsl;
         diesel::update(dsl::users.find(id))
             .set(dsl::lang.eq(code))
-            .execute(&mut self.connection)
+            .execute(connection)
             .context("changing lang for a user")?;
+        DATABASE_CACHE.user_changed(id)?;
 
         log::trace!("changed user with id {id}'s language to {code}");
 
@@ -278,12 +792,17 @@ impl Database {
     }
 
     /// Report a carta
-    pub fn report_carta(&mut self, uuid: &str) -> anyhow::Result<()> {
+    pub fn report_carta(connection: &mut DbConnection, uuid: Uuid) -> anyhow::Result<()> {
         use crate::schema::cartas::dsl;
-        diesel::update(dsl::cartas.filter(dsl::uuid.eq(uuid)))
+        let carta = diesel::update(dsl::cartas.filter(dsl::uuid.eq(SqlUuid::from(uuid))))
             .set(dsl::reports.eq(dsl::reports + 1))
-            .execute(&mut self.connection)
+            .get_result::<Carta>(connection)
+            .optional()
             .context("reporting a carta")?;
+        if let Some(carta) = carta {
+            let root_id = Self::thread_root(connection, &carta)?;
+            DATABASE_CACHE.carta_changed(&carta, root_id)?;
+        }
 
         log::trace!("reported carta with uuid {uuid}");
 
@@ -293,7 +812,7 @@ impl Database {
     /// Insert a new carta
     #[allow(clippy::too_many_arguments)]
     pub fn insert_carta(
-        &mut self,
+        connection: &mut DbConnection,
         user_id: Option<i32>,
         parent: Option<i32>,
         content: String,
@@ -301,14 +820,18 @@ impl Database {
         from: Option<String>,
         lang: &Lang,
         ip: String,
+        visibility: Visibility,
+        lifetime: Option<Duration>,
     ) -> anyhow::Result<Carta> {
         // Generate 6-digit modification PIN
         let uniform = Uniform::new('0', '9');
         let mut rng = thread_rng();
         let modification_code = (0..6).map(|_| uniform.sample(&mut rng)).collect();
 
+        let creation = Utc::now();
+
         let update = CartaUpdate {
-            uuid: uuid::Uuid::new_v4().to_string(),
+            uuid: Uuid::new_v4(),
             user_id,
             parent,
             title,
@@ -316,21 +839,49 @@ impl Database {
             content,
             lang: lang.code.clone(),
             random_accessible: parent.is_none(),
-            creation: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as _,
+            creation,
             modification: None,
             modification_code,
             reports: 0,
             ip,
+            visibility: visibility as _,
+            expiration: lifetime.map(|lifetime| creation + lifetime),
+            views: 0,
+            replies: 0,
+            last_reply: None,
         };
 
         use crate::schema::cartas::dsl;
-        let carta = update
-            .insert_into(dsl::cartas)
-            .returning(Carta::as_returning())
-            .get_result(&mut self.connection)?;
+        let (carta, counted_parent) = connection.transaction(|connection| {
+            let carta = update
+                .insert_into(dsl::cartas)
+                .returning(Carta::as_returning())
+                .get_result(connection)?;
+
+            // Keep the parent's reply count up to date
+            let mut counted_parent = None;
+            if let (Some(parent), Visibility::Public) = (parent, visibility) {
+                counted_parent = Some(
+                    diesel::update(dsl::cartas.find(parent))
+                        .set((
+                            dsl::replies.eq(dsl::replies + 1),
+                            dsl::last_reply.eq(carta.creation),
+                        ))
+                        .get_result::<Carta>(connection)
+                        .context("counting reply")?,
+                );
+            }
+
+            anyhow::Ok((carta, counted_parent))
+        })?;
+        // The thread replied to has a new carta, and its parent may have a new count
+        if parent.is_some() {
+            let root_id = Self::thread_root(connection, &carta)?;
+            match counted_parent {
+                Some(counted_parent) => DATABASE_CACHE.carta_changed(&counted_parent, root_id)?,
+                None => DATABASE_CACHE.thread_changed(root_id)?,
+            }
+        }
 
         log::trace!("inserted carta {id}", id = carta.id);
 
@@ -338,11 +889,11 @@ impl Database {
     }
 
     /// Fetch a carta from its ID
-    pub fn fetch_carta(&mut self, id: i32) -> anyhow::Result<Carta> {
+    pub fn fetch_carta(connection: &mut DbConnection, id: i32) -> anyhow::Result<Carta> {
         use crate::schema::cartas::dsl;
         let carta = dsl::cartas
             .find(id)
-            .get_result(&mut self.connection)
+            .get_result(connection)
             .with_context(|| anyhow!("fetching carta with id {id}"))?;
 
         log::trace!("fetched carta with id {id}");
@@ -352,7 +903,7 @@ impl Database {
 
     /// Redact or delete a carta's content if the ID and pin match
     pub fn redact_carta(
-        &mut self,
+        connection: &mut DbConnection,
         id: i32,
         pin: &str,
         redact_text: &str,
@@ -363,42 +914,87 @@ impl Database {
                 .filter(dsl::modification_code.eq(pin))
                 .filter(dsl::id.eq(id)),
         )
-        .set((
-            dsl::random_accessible.eq(false),
-            dsl::user_id.eq(Option::<i32>::None),
-            dsl::content.eq(redact_text),
-            dsl::title.eq(Some(redact_text)),
-            dsl::sender.eq(Some(redact_text)),
-            dsl::modification.eq(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i32),
-        ))
-        .get_result(&mut self.connection)
+        .set(Redaction::new(redact_text, Utc::now()))
+        .get_result::<Carta>(connection)
         .optional()?;
+        if let Some(carta) = &carta {
+            let root_id = Self::thread_root(connection, carta)?;
+            DATABASE_CACHE.carta_changed(carta, root_id)?;
+        }
 
         log::trace!("redacted carta with id {id} to `{redact_text}`");
 
         Ok(carta)
     }
 
-    /// Fetch cartas from a user ID
-    pub fn fetch_cartas(&mut self, id: i32) -> anyhow::Result<Vec<Carta>> {
+    /// Redact all cartas whose lifetime has passed, returning the redacted cartas
+    pub fn expire_cartas(
+        connection: &mut DbConnection,
+        redact_text: &str,
+    ) -> anyhow::Result<Vec<Carta>> {
+        let now = Utc::now();
+
+        use crate::schema::cartas::dsl;
+        let cartas = diesel::update(dsl::cartas.filter(dsl::expiration.le(now)))
+            .set((
+                Redaction::new(redact_text, now),
+                dsl::expiration.eq(Option::<DateTime<Utc>>::None),
+            ))
+            .get_results::<Carta>(connection)
+            .context("expiring cartas")?;
+        for carta in &cartas {
+            let root_id = Self::thread_root(connection, carta)?;
+            DATABASE_CACHE.carta_changed(carta, root_id)?;
+        }
+
+        log::trace!("expired {count} cartas", count = cartas.len());
+
+        Ok(cartas)
+    }
+
+    /// Fetch a page of cartas from a user ID. One more carta than the page size is
+    /// fetched if there's another page after this one.
+    pub fn fetch_cartas(
+        connection: &mut DbConnection,
+        id: i32,
+        sort: CartaSort,
+        filter: CartaFilter,
+        page: usize,
+    ) -> anyhow::Result<Vec<Carta>> {
         use crate::schema::cartas::dsl;
-        let cartas = dsl::cartas
+        let mut query = dsl::cartas
             .filter(dsl::user_id.eq(Some(id)))
-            .get_results(&mut self.connection)
+            .select(Carta::as_select())
+            .into_boxed();
+        query = match filter.kind {
+            CartaKind::All => query,
+            CartaKind::TopLevel => query.filter(dsl::parent.is_null()),
+            CartaKind::Replies => query.filter(dsl::parent.is_not_null()),
+        };
+        query = match sort {
+            CartaSort::Newest => query.order((dsl::creation.desc(), dsl::id.desc())),
+            CartaSort::Oldest => query.order((dsl::creation.asc(), dsl::id.asc())),
+            CartaSort::MostReplies => query.order((dsl::replies.desc(), dsl::id.desc())),
+            CartaSort::MostReported => query.order((dsl::reports.desc(), dsl::id.desc())),
+        };
+        let cartas = query
+            .offset((page * VIEW_CARTAS_PAGE_SIZE) as _)
+            .limit(VIEW_CARTAS_PAGE_SIZE as i64 + 1)
+            .load(connection)
             .with_context(|| anyhow!("fetching carta with from user id {id}"))?;
 
-        log::trace!("fetched cartas from user id {id}");
+        log::trace!("fetched page {page} of cartas from user id {id}");
 
         Ok(cartas)
     }
 
     /// Fetch a carta from its UUID
-    pub fn fetch_carta_uuid(&mut self, uuid: &str) -> anyhow::Result<Carta> {
+    pub fn fetch_carta_uuid(connection: &mut DbConnection, uuid: Uuid) -> anyhow::Result<Carta> {
         use crate::schema::cartas::dsl;
         let carta = dsl::cartas
-            .filter(dsl::uuid.eq(uuid))
+            .filter(dsl::uuid.eq(SqlUuid::from(uuid)))
             .select(Carta::as_select())
-            .first(&mut self.connection)
+            .first(connection)
             .with_context(|| anyhow!("fetching carta with uuid {uuid}"))?;
 
         log::trace!("fetched carta with id {id}: {carta:?}", id = carta.id);
@@ -406,60 +1002,508 @@ impl Database {
         Ok(carta)
     }
 
-    /// Fetch a tree of all cartas from a carta ID
-    /// fixme: currently untested. i don't know if this will work.
-    pub fn fetch_carta_tree(&mut self, id: i32) -> anyhow::Result<TreeBranch<Carta>> {
-        // fixme: this is quite an inefficient solution. we traverse to the top from
-        // the starting id and *then* build the tree, not caching any results. more
-        // database calls than necessary occur.
+    /// Fetch the ID of the top-level carta a carta replies to, directly or not
+    pub fn fetch_thread_root(connection: &mut DbConnection, id: i32) -> anyhow::Result<i32> {
+        #[derive(QueryableByName)]
+        struct ThreadRoot {
+            #[diesel(sql_type = Integer)]
+            id: i32,
+        }
 
-        let mut current_node = self.fetch_carta(id)?;
+        let root = diesel::sql_query(format!(
+            "with recursive ancestors as (
+                select id, parent from cartas where id = {FIRST_BIND}
+                union all
+                select cartas.id, cartas.parent from cartas
+                join ancestors on cartas.id = ancestors.parent
+            )
+            select id from ancestors where parent is null"
+        ))
+        .bind::<Integer, _>(id)
+        .get_result::<ThreadRoot>(connection)
+        .with_context(|| anyhow!("fetching thread root of carta with id {id}"))?;
 
-        // Traverse to top of tree
-        while let Some(parent_id) = current_node.parent {
-            current_node = self.fetch_carta(parent_id)?;
-        }
-        let tree = TreeBranch::<Carta> {
-            node: current_node,
-            parent: None,
-            children: vec![].into(),
-        };
+        log::trace!("carta with id {id} has root {root}", root = root.id);
 
-        // DFS to build tree
-        let self_ref = RefCell::new(self);
-        let traverse_downward = fix_fn!(|traverse_downward,
-                                         branch: Rc<TreeBranch<Carta>>|
-         -> anyhow::Result<()> {
-            for child in {
-                // We need this borrow guard to be dropped before we iterate through
-                let mut self_borrow = self_ref.borrow_mut();
-                self_borrow.fetch_carta_children(branch.node.id)?
-            } {
-                let child_branch = TreeBranch {
-                    node: child,
-                    parent: Some(Rc::downgrade(&branch)),
-                    children: vec![].into(),
-                };
-                let child_branch_ref = Rc::new(child_branch);
-                branch.children.borrow_mut().push(child_branch_ref.clone());
-                traverse_downward(child_branch_ref)?;
-            }
+        Ok(root.id)
+    }
 
-            Ok(())
-        });
+    /// Find the ID of the top-level carta of a carta's thread, from the cache if
+    /// possible
+    pub fn thread_root(connection: &mut DbConnection, carta: &Carta) -> anyhow::Result<i32> {
+        let Some(parent) = carta.parent else {
+            return Ok(carta.id);
+        };
+        let cache = &DATABASE_CACHE.root;
+        if let Some(root_id) = cache.lookup(&carta.id)? {
+            return Ok(*root_id);
+        }
+        let since = cache.generation()?;
+        let root_id = Self::fetch_thread_root(connection, parent)?;
+        Ok(*cache.insert(carta.id, root_id, since)?)
+    }
 
-        let tree_ref = Rc::new(tree);
-        traverse_downward(Rc::clone(&tree_ref))?;
+    /// Fetch a tree of all cartas in a thread from its top-level carta ID. Private
+    /// replies are included, so callers must check visibility with
+    /// [`Carta::visible_to`] before showing them.
+    pub fn fetch_carta_tree(
+        connection: &mut DbConnection,
+        root_id: i32,
+    ) -> anyhow::Result<Tree<Carta>> {
+        let thread = diesel::sql_query(format!(
+            "with recursive thread as (
+                select cartas.* from cartas where cartas.id = {FIRST_BIND}
+                union all
+                select cartas.* from cartas
+                join thread on cartas.parent = thread.id
+            )
+            select * from thread"
+        ))
+        .bind::<Integer, _>(root_id)
+        .load::<Carta>(connection)
+        .with_context(|| anyhow!("fetching carta tree from carta with id {root_id}"))?;
 
-        Rc::into_inner(tree_ref).context("tree had more than one ref")
+        log::trace!(
+            "fetched {count} cartas in the tree from carta with id {root_id}",
+            count = thread.len()
+        );
+
+        assemble_carta_tree(root_id, thread)
     }
 
-    /// Helper function to find all children of a parent
-    fn fetch_carta_children(&mut self, id: i32) -> anyhow::Result<Vec<Carta>> {
+    /// Helper function to query the replies others have written to a user's cartas,
+    /// for the mailbox and its count
+    fn replies_to(user_id: i32) -> crate::schema::cartas::BoxedQuery<'static, Backend> {
         use crate::schema::cartas::dsl;
+        let parents = diesel::alias!(crate::schema::cartas as parents);
         dsl::cartas
-            .filter(dsl::parent.eq(id))
-            .load(&mut self.connection)
-            .with_context(|| anyhow!("finding children of carta with id {id}"))
+            .filter(
+                dsl::parent.eq_any(
+                    parents
+                        .filter(parents.field(dsl::user_id).eq(Some(user_id)))
+                        .select(parents.field(dsl::id).nullable()),
+                ),
+            )
+            .filter(dsl::user_id.is_null().or(dsl::user_id.ne(user_id)))
+            .into_boxed()
+    }
+
+    /// Fetch the newest replies others have written to a user's cartas
+    pub fn fetch_replies(
+        connection: &mut DbConnection,
+        user_id: i32,
+    ) -> anyhow::Result<Vec<Carta>> {
+        use crate::schema::cartas::dsl;
+        let replies = Self::replies_to(user_id)
+            .select(Carta::as_select())
+            .order(dsl::creation.desc())
+            .limit(MAX_MAILBOX_LEN)
+            .load(connection)
+            .with_context(|| anyhow!("fetching replies to user id {user_id}"))?;
+
+        log::trace!("fetched replies to user id {user_id}");
+
+        Ok(replies)
+    }
+
+    /// Count the replies to a user's cartas written since they last read their mailbox
+    pub fn count_unread_replies(
+        connection: &mut DbConnection,
+        user_id: i32,
+    ) -> anyhow::Result<i64> {
+        let last_read = Self::fetch_last_read(connection, user_id)?;
+
+        use crate::schema::cartas::dsl;
+        let unread = Self::replies_to(user_id)
+            .filter(dsl::creation.gt(last_read))
+            .count()
+            .get_result(connection)
+            .with_context(|| anyhow!("counting unread replies to user id {user_id}"))?;
+
+        Ok(unread)
+    }
+
+    /// Fetch when a user last read their mailbox. Never reading it is the unix epoch.
+    pub fn fetch_last_read(
+        connection: &mut DbConnection,
+        user_id: i32,
+    ) -> anyhow::Result<DateTime<Utc>> {
+        use crate::schema::mailbox_reads::dsl;
+        let last_read = dsl::mailbox_reads
+            .find(user_id)
+            .select(dsl::last_read)
+            .first(connection)
+            .optional()
+            .with_context(|| anyhow!("fetching last read time for user id {user_id}"))?;
+
+        Ok(last_read.unwrap_or(DateTime::UNIX_EPOCH))
+    }
+
+    /// Mark all replies in a user's mailbox as read
+    pub fn mark_replies_read(connection: &mut DbConnection, user_id: i32) -> anyhow::Result<()> {
+        let now = Utc::now();
+
+        use crate::schema::mailbox_reads::dsl;
+        diesel::insert_into(dsl::mailbox_reads)
+            .values((dsl::user_id.eq(user_id), dsl::last_read.eq(now)))
+            .on_conflict(dsl::user_id)
+            .do_update()
+            .set(dsl::last_read.eq(now))
+            .execute(connection)
+            .context("marking replies as read")?;
+
+        log::trace!("marked replies to user id {user_id} as read");
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A public carta with nothing but its place in a thread
+    fn carta(id: i32, parent: Option<i32>) -> Carta {
+        Carta {
+            id,
+            uuid: Uuid::new_v4(),
+            parent,
+            user_id: None,
+            title: None,
+            sender: None,
+            content: format!("carta {id}"),
+            modification_code: "000000".to_string(),
+            creation: DateTime::UNIX_EPOCH,
+            modification: None,
+            lang: "en".to_string(),
+            random_accessible: parent.is_none(),
+            reports: 0,
+            ip: "127.0.0.1".to_string(),
+            visibility: Visibility::Public as _,
+            expiration: None,
+            views: 0,
+            replies: 0,
+            last_reply: None,
+        }
+    }
+
+    #[test]
+    fn assembles_deep_thread() {
+        let depth = 5000;
+        let thread = (1..=depth)
+            .map(|id| carta(id, (id > 1).then_some(id - 1)))
+            .rev()
+            .collect();
+
+        let tree = assemble_carta_tree(1, thread).unwrap();
+        assert_eq!(tree.len(), depth as usize);
+        let deepest = tree.find(|carta| carta.id == depth).unwrap();
+        assert!(tree.children(deepest).is_empty());
+        let ancestors = tree
+            .ancestors(deepest)
+            .map(|node| tree[node].id)
+            .collect::<Vec<_>>();
+        assert_eq!(ancestors, (1..depth).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn assembles_wide_thread_in_id_order() {
+        let width = 5000;
+        let mut thread = vec![carta(1, None)];
+        thread.extend((2..=width + 1).rev().map(|id| carta(id, Some(1))));
+
+        let tree = assemble_carta_tree(1, thread).unwrap();
+        let replies = tree
+            .children(tree.root())
+            .iter()
+            .map(|&node| tree[node].id)
+            .collect::<Vec<_>>();
+        assert_eq!(replies, (2..=width + 1).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn assembles_nested_replies_in_id_order() {
+        let thread = vec![
+            carta(7, Some(3)),
+            carta(3, Some(1)),
+            carta(5, Some(3)),
+            carta(1, None),
+            carta(2, Some(1)),
+            carta(4, Some(2)),
+        ];
+
+        let tree = assemble_carta_tree(1, thread).unwrap();
+        let order = tree
+            .dfs(tree.root())
+            .map(|node| tree[node].id)
+            .collect::<Vec<_>>();
+        assert_eq!(order, [1, 2, 4, 3, 5, 7]);
+    }
+
+    #[test]
+    fn leaves_out_cartas_missing_their_parent() {
+        let thread = vec![carta(1, None), carta(2, Some(1)), carta(4, Some(3))];
+
+        let tree = assemble_carta_tree(1, thread).unwrap();
+        assert_eq!(tree.len(), 2);
+        assert!(tree.find(|carta| carta.id == 4).is_none());
+    }
+
+    #[test]
+    fn assembles_thread_under_a_reply() {
+        let thread = vec![carta(2, Some(1)), carta(3, Some(2))];
+
+        let tree = assemble_carta_tree(2, thread).unwrap();
+        assert_eq!(tree[tree.root()].id, 2);
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[test]
+    fn fails_without_root() {
+        assert!(assemble_carta_tree(1, vec![carta(2, Some(1))]).is_err());
+    }
+
+    #[tokio::test]
+    async fn never_caches_what_changed_while_loading() {
+        let cache = DatabaseCache::new();
+        let loaded = carta(1, None);
+        let uuid = loaded.uuid;
+
+        // The carta changes between a reader loading it and caching it
+        DatabaseCache::get_or_else(&cache.carta, &uuid, || {
+            cache.carta_changed(&loaded, loaded.id).unwrap();
+            std::future::ready(anyhow::Ok(loaded.clone()))
+        })
+        .await
+        .unwrap();
+        assert!(cache.carta.lookup(&uuid).unwrap().is_none());
+
+        // Same for its thread
+        DatabaseCache::get_or_else(&cache.thread, &loaded.id, || {
+            cache.thread_changed(loaded.id).unwrap();
+            std::future::ready(assemble_carta_tree(loaded.id, vec![loaded.clone()]))
+        })
+        .await
+        .unwrap();
+        assert!(cache.thread.lookup(&loaded.id).unwrap().is_none());
+
+        // Nothing changes while loading it again, so now it's cached
+        DatabaseCache::get_or_else(&cache.carta, &uuid, || {
+            std::future::ready(anyhow::Ok(loaded.clone()))
+        })
+        .await
+        .unwrap();
+        assert!(cache.carta.lookup(&uuid).unwrap().is_some());
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use super::*;
+        use crate::i18n::ENGLISH;
+
+        use diesel::connection::SimpleConnection as _;
+        use std::sync::atomic::AtomicI32;
+
+        /// A fresh in-memory database with every migration applied. Tests share
+        /// [`DATABASE_CACHE`], whose threads and roots are keyed by carta id, so each
+        /// database starts its ids somewhere else.
+        ///
+        /// These tests only run with `--features sqlite`.
+        fn connection() -> DbConnection {
+            static DATABASES: AtomicI32 = AtomicI32::new(1);
+
+            let mut connection = DbConnection::establish(":memory:").unwrap();
+            connection
+                .batch_execute("pragma foreign_keys = on;")
+                .unwrap();
+            connection.run_pending_migrations(MIGRATIONS).unwrap();
+            connection
+                .batch_execute(&format!(
+                    "insert into cartas (id, uuid, content, modification_code, creation, lang,
+                        random_accessible, reports, ip)
+                    values ({id}, '{uuid}', '', '000000', '1970-01-01 00:00:00', 'en',
+                        false, 0, '127.0.0.1');",
+                    id = DATABASES.fetch_add(1, Ordering::Relaxed) * 1_000_000,
+                    uuid = Uuid::new_v4(),
+                ))
+                .unwrap();
+            connection
+        }
+
+        fn insert(connection: &mut DbConnection, parent: Option<i32>) -> Carta {
+            insert_with(connection, parent, Visibility::Public, None)
+        }
+
+        fn insert_with(
+            connection: &mut DbConnection,
+            parent: Option<i32>,
+            visibility: Visibility,
+            lifetime: Option<Duration>,
+        ) -> Carta {
+            Database::insert_carta(
+                connection,
+                None,
+                parent,
+                "carta".to_string(),
+                None,
+                None,
+                &ENGLISH,
+                "127.0.0.1".to_string(),
+                visibility,
+                lifetime,
+            )
+            .unwrap()
+        }
+
+        /// Read a carta the way pages do, through the cache
+        async fn read_carta(connection: &mut DbConnection, uuid: Uuid) -> Arc<Carta> {
+            DatabaseCache::get_or_else(&DATABASE_CACHE.carta, &uuid, || {
+                std::future::ready(Database::fetch_carta_uuid(connection, uuid))
+            })
+            .await
+            .unwrap()
+        }
+
+        /// Read a thread the way pages do, through the cache
+        async fn read_thread(connection: &mut DbConnection, root_id: i32) -> Arc<Tree<Carta>> {
+            DatabaseCache::get_or_else(&DATABASE_CACHE.thread, &root_id, || {
+                std::future::ready(Database::fetch_carta_tree(connection, root_id))
+            })
+            .await
+            .unwrap()
+        }
+
+        #[test]
+        fn fetches_whole_thread() {
+            let mut connection = connection();
+            let root = insert(&mut connection, None);
+            let other = insert(&mut connection, None);
+            insert(&mut connection, Some(other.id));
+
+            // A deep branch and a wide one
+            let mut deepest = root.id;
+            for _ in 0..1000 {
+                deepest = insert(&mut connection, Some(deepest)).id;
+            }
+            let wide = insert(&mut connection, Some(root.id));
+            let replies = (0..1000)
+                .map(|_| insert(&mut connection, Some(wide.id)).id)
+                .collect::<Vec<_>>();
+
+            let tree = Database::fetch_carta_tree(&mut connection, root.id).unwrap();
+            assert_eq!(tree.len(), 1 + 1000 + 1 + 1000);
+            assert!(tree.find(|carta| carta.parent == Some(other.id)).is_none());
+
+            let deepest = tree.find(|carta| carta.id == deepest).unwrap();
+            assert_eq!(tree.ancestors(deepest).count(), 1000);
+            let wide_node = tree.find(|carta| carta.id == wide.id).unwrap();
+            let children = tree
+                .children(wide_node)
+                .iter()
+                .map(|&node| tree[node].id)
+                .collect::<Vec<_>>();
+            assert_eq!(children, replies);
+
+            let subtree = Database::fetch_carta_tree(&mut connection, wide.id).unwrap();
+            assert_eq!(subtree.len(), 1001);
+        }
+
+        /// Read a user the way clients are set up, through the cache
+        async fn read_user(connection: &mut DbConnection, hash: &[u8; CERT_HASH_LEN]) -> Arc<User> {
+            DatabaseCache::get_or_else(&DATABASE_CACHE.user, hash, || {
+                std::future::ready(Database::insert_user(connection, "en".to_string(), hash))
+            })
+            .await
+            .unwrap()
+        }
+
+        #[tokio::test]
+        async fn never_serves_stale_cartas() {
+            let mut connection = connection();
+            let root = insert(&mut connection, None);
+            assert_eq!(read_carta(&mut connection, root.uuid).await.reports, 0);
+            assert_eq!(read_thread(&mut connection, root.id).await.len(), 1);
+
+            Database::report_carta(&mut connection, root.uuid).unwrap();
+            assert_eq!(read_carta(&mut connection, root.uuid).await.reports, 1);
+
+            let reply = insert(&mut connection, Some(root.id));
+            assert_eq!(read_carta(&mut connection, root.uuid).await.replies, 1);
+            assert_eq!(read_thread(&mut connection, root.id).await.len(), 2);
+
+            // Private replies aren't counted, but are still part of the thread
+            insert_with(&mut connection, Some(reply.id), Visibility::Private, None);
+            assert_eq!(read_carta(&mut connection, root.uuid).await.replies, 1);
+            assert_eq!(read_thread(&mut connection, root.id).await.len(), 3);
+
+            Database::redact_carta(&mut connection, reply.id, &reply.modification_code, "gone")
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                read_carta(&mut connection, reply.uuid).await.content,
+                "gone"
+            );
+            let thread = read_thread(&mut connection, root.id).await;
+            let redacted = thread.find(|carta| carta.id == reply.id).unwrap();
+            assert_eq!(thread[redacted].content, "gone");
+
+            let expiring = insert_with(
+                &mut connection,
+                Some(root.id),
+                Visibility::Public,
+                Some(Duration::ZERO),
+            );
+            assert_eq!(
+                read_carta(&mut connection, expiring.uuid).await.content,
+                "carta"
+            );
+            Database::expire_cartas(&mut connection, "expired").unwrap();
+            assert_eq!(
+                read_carta(&mut connection, expiring.uuid).await.content,
+                "expired"
+            );
+        }
+
+        #[tokio::test]
+        async fn never_caches_cartas_changed_while_loading() {
+            let mut connection = connection();
+            let carta = insert(&mut connection, None);
+
+            // The carta is reported between a reader loading it and caching it
+            let loaded = DatabaseCache::get_or_else(&DATABASE_CACHE.carta, &carta.uuid, || {
+                let loaded = Database::fetch_carta_uuid(&mut connection, carta.uuid);
+                Database::report_carta(&mut connection, carta.uuid).unwrap();
+                std::future::ready(loaded)
+            })
+            .await
+            .unwrap();
+            assert_eq!(loaded.reports, 0);
+            assert_eq!(read_carta(&mut connection, carta.uuid).await.reports, 1);
+
+            // Same for a thread replied to
+            let thread = DatabaseCache::get_or_else(&DATABASE_CACHE.thread, &carta.id, || {
+                let loaded = Database::fetch_carta_tree(&mut connection, carta.id);
+                insert(&mut connection, Some(carta.id));
+                std::future::ready(loaded)
+            })
+            .await
+            .unwrap();
+            assert_eq!(thread.len(), 1);
+            assert_eq!(read_thread(&mut connection, carta.id).await.len(), 2);
+        }
+
+        #[tokio::test]
+        async fn never_serves_stale_users() {
+            let mut connection = connection();
+            let hash = [0x50; CERT_HASH_LEN];
+            assert_eq!(read_user(&mut connection, &hash).await.lang, "en");
+
+            let id = Database::fetch_user(&mut connection, &hash)
+                .unwrap()
+                .unwrap()
+                .id;
+            Database::change_language(&mut connection, id, "es").unwrap();
+            assert_eq!(read_user(&mut connection, &hash).await.lang, "es");
+        }
     }
 }
@@ -11,6 +11,8 @@ pub struct Lang {
     /* Carta */
     pub untitled_sentinel: String,
     pub from_sentinel: String,
+    pub replies_text: String,
+    pub last_reply_text: String,
     /* Index page */
     pub index_header: String,
     pub index_about_header: String,
@@ -54,6 +56,12 @@ pub struct Lang {
     pub write_link: String,
     pub return_link: String,
     pub no_new_cartas_status: String,
+    pub forget_seen_link: String,
+    pub forget_seen_flash: String,
+    pub pinned_marker: String,
+    pub page_text: String,
+    pub newer_page_link: String,
+    pub older_page_link: String,
     /* Submit confirmation page */
     pub cancel_link: String,
     pub submit_confirmation_link: String,
@@ -77,22 +85,70 @@ pub struct Lang {
     pub write_hide_line_numbers_link: String,
     pub write_show_line_numbers_link: String,
     pub write_too_long: String,
+    pub write_private_link: String,
+    pub write_lifetime_link: String,
+    pub lifetime_forever: String,
+    pub lifetime_day: String,
+    pub lifetime_week: String,
+    pub write_public_link: String,
     /* View page */
     pub view_header: String,
     pub view_replies_header: String,
+    pub continue_thread_link: String,
+    pub more_replies_link: String,
+    pub view_thread_link: String,
     pub view_add_reply_link: String,
     pub view_report_link: String,
     pub report_submitted_flash: String,
     pub delete_code_text: String,
+    pub private_marker: String,
+    pub view_private_text: String,
+    pub view_expiration_text: String,
+    pub pin_link: String,
+    pub unpin_link: String,
+    pub dismiss_link: String,
+    pub permalink_link: String,
+    /* Thread page */
+    pub thread_header: String,
+    pub thread_order_link: String,
+    pub thread_order_tree: String,
+    pub thread_order_chronological: String,
+    pub thread_read_link: String,
     /* View cartas page */
     pub all_header: String,
     pub all_empty_text: String,
+    pub view_sort_link: String,
+    pub sort_newest: String,
+    pub sort_oldest: String,
+    pub sort_most_replies: String,
+    pub sort_most_reported: String,
+    pub view_kind_link: String,
+    pub kind_all: String,
+    pub kind_top_level: String,
+    pub kind_replies: String,
+    /* Mailbox page */
+    pub mailbox_link: String,
+    pub mailbox_unread_text: String,
+    pub mailbox_header: String,
+    pub mailbox_empty_text: String,
+    pub mailbox_new_marker: String,
+    pub mailbox_mark_read_link: String,
+    pub mailbox_cert_required_flash: String,
+    pub mailbox_feed_link: String,
+    pub mailbox_atom_feed_link: String,
+    /* Carta permalink page */
+    pub permalink_unavailable_text: String,
+    /* Feed page */
+    pub feed_header: String,
+    pub feed_subheader: String,
+    pub feed_invalid_text: String,
     /* Delete cartas page */
     pub delete_header: String,
     pub delete_instructions_text: String,
     pub delete_code_link: String,
     pub code_input: String,
     pub deleted: String,
+    pub expired: String,
     pub removed: String,
     pub deletion_successful: String,
     pub deletion_failure: String,
@@ -1,50 +1,57 @@
 //! Abyss
 
-#![allow(incomplete_features)]
-#![feature(inherent_associated_types)]
-
 use crate::abyss::handle_client_in_abyss;
-use crate::consts::FOOTER;
+use crate::consts::{default_carta, ensure_lazily_loaded_constants_work, FOOTER};
+use crate::database::{run_migrations, DatabaseUnavailable};
 use crate::i18n::{lookup_lang_from_code, Lang};
 
 use components::certificate::require_certificate;
-use consts::PERIODIC_PRUNE_SECS;
+use consts::{
+    DATABASE_RETRY_BACKOFF_MILLIS, FEED_SECRET, PERIODIC_CACHE_SWEEP_SECS, PERIODIC_EXPIRE_SECS,
+    PERIODIC_PRUNE_SECS, STARTUP_MAX_BACKOFF_SECS,
+};
+use database::{prune_expired_cartas, DATABASE_CACHE};
 use dotenvy::dotenv;
 use i18n::ensure_lazily_loaded_languages_work;
 use state::ClientState;
 use std::net::SocketAddr;
 use std::time::Duration;
-use tokio::spawn;
+use tokio::{spawn, task::spawn_blocking};
 use windmark::context::RouteContext;
 
 pub mod abyss;
+pub mod backend;
+pub mod cache;
 pub mod components;
 pub mod consts;
 pub mod database;
 pub mod i18n;
+#[cfg_attr(feature = "sqlite", path = "schema_sqlite.rs")]
 pub mod schema;
 pub mod state;
 pub mod tree;
 
+/// Respond to an error, telling the client to come back later if the database is down
+fn error_to_response(e: anyhow::Error) -> windmark::response::Response {
+    if e.chain().any(|cause| cause.is::<DatabaseUnavailable>()) {
+        log::warn!("{e:#}");
+        return windmark::response::Response::server_unavailable(
+            "the abyss is unreachable right now, try again later",
+        );
+    }
+    log::error!("{e:#?}");
+    windmark::response::Response::temporary_failure(format!("error! {e}"))
+}
 pub fn result_to_response(result: anyhow::Result<String>) -> windmark::response::Response {
     match result {
         Ok(res) => windmark::response::Response::success(res),
-        Err(e) => {
-            log::error!("{e:#?}");
-            windmark::response::Response::temporary_failure(format!("error! {e}"))
-        }
+        Err(e) => error_to_response(e),
     }
 }
 pub fn windmark_response_result_to_response(
     result: anyhow::Result<windmark::response::Response>,
 ) -> windmark::response::Response {
-    match result {
-        Ok(res) => res,
-        Err(e) => {
-            log::error!("{e:#?}");
-            windmark::response::Response::temporary_failure(format!("error! {e}"))
-        }
-    }
+    result.unwrap_or_else(error_to_response)
 }
 
 pub fn display_ip(ip: Option<&SocketAddr>) -> String {
@@ -82,8 +89,50 @@ macro_rules! log {
 async fn main() -> anyhow::Result<()> {
     ensure_lazily_loaded_languages_work();
     dotenv()?;
+    ensure_lazily_loaded_constants_work();
     pretty_env_logger::init();
 
+    let mut migrate_only = false;
+    let mut dry_run = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--migrate-only" => migrate_only = true,
+            // Implies `--migrate-only`
+            "--dry-run" => dry_run = true,
+            _ => Err(anyhow::anyhow!(
+                "unknown argument `{arg}`, expected `--migrate-only` or `--dry-run`"
+            ))?,
+        }
+    }
+
+    // Never serve on a schema this version wasn't written for. The database may still
+    // be starting along with the server, so wait for it unless only migrating.
+    let mut backoff = Duration::from_millis(DATABASE_RETRY_BACKOFF_MILLIS);
+    loop {
+        match spawn_blocking(move || run_migrations(dry_run)).await? {
+            Ok(()) => break,
+            Err(e)
+                if !migrate_only
+                    && !dry_run
+                    && e.downcast_ref::<DatabaseUnavailable>().is_some() =>
+            {
+                log::warn!("{e:#}, retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(Duration::from_secs(STARTUP_MAX_BACKOFF_SECS));
+            }
+            Err(e) => Err(e)?,
+        }
+    }
+    if migrate_only || dry_run {
+        return Ok(());
+    }
+
+    // Only serving feeds needs the secret, so setting up the database works without it
+    lazy_static::initialize(&FEED_SECRET);
+
+    // Load the default carta up front now that the database is up
+    default_carta().await?;
+
     // Periodically prune old clients
     spawn(async move {
         loop {
@@ -91,6 +140,24 @@ async fn main() -> anyhow::Result<()> {
             ClientState::prune_clients().unwrap();
         }
     });
+    // Periodically redact expired cartas
+    spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(PERIODIC_EXPIRE_SECS as _)).await;
+            if let Err(e) = prune_expired_cartas().await {
+                log::error!("{e:#?}");
+            }
+        }
+    });
+    // Periodically drop stale cache entries
+    spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(PERIODIC_CACHE_SWEEP_SECS as _)).await;
+            if let Err(e) = DATABASE_CACHE.sweep() {
+                log::error!("{e:#?}");
+            }
+        }
+    });
 
     let index_handle = |context| {
         let lang = lang!(context);
@@ -103,29 +170,46 @@ async fn main() -> anyhow::Result<()> {
         log!(context);
         result_to_response(components::pages::terms::terms(context, lang))
     };
-    let abyss_handle = |context| {
+    let abyss_handle = |context| async move {
         let lang = lang!(context);
         if let Err(resp) = require_certificate(&context, lang) {
             return resp;
         };
         log!(context);
-        windmark_response_result_to_response(handle_client_in_abyss(context, lang, true))
+        windmark_response_result_to_response(handle_client_in_abyss(context, lang, true).await)
     };
-    let certless_abyss_handle = |context| {
+    let certless_abyss_handle = |context| async move {
         let lang = lang!(context);
         log!(context);
-        windmark_response_result_to_response(handle_client_in_abyss(context, lang, false))
+        windmark_response_result_to_response(handle_client_in_abyss(context, lang, false).await)
     };
     let certless_handle = |context| {
         let lang = lang!(context);
         log!(context);
         result_to_response(components::pages::certless::certless(context, lang))
     };
-    let delete_handle = |context| {
+    let carta_handle = |context| async move {
+        let lang = lang!(context);
+        log!(context);
+        windmark_response_result_to_response(components::pages::carta::carta(context, lang).await)
+    };
+    let feed_handle = |context| async move {
+        let lang = lang!(context);
+        log!(context);
+        windmark_response_result_to_response(components::pages::feed::feed(context, lang).await)
+    };
+    let atom_feed_handle = |context| async move {
+        let lang = lang!(context);
+        log!(context);
+        windmark_response_result_to_response(
+            components::pages::feed::atom_feed(context, lang).await,
+        )
+    };
+    let delete_handle = |context| async move {
         let lang = lang!(context);
         log!(context);
         windmark_response_result_to_response(
-            components::pages::abyss::delete_carta::handle_deleting_cartas(context, lang),
+            components::pages::abyss::delete_carta::handle_deleting_cartas(context, lang).await,
         )
     };
 
@@ -167,6 +251,14 @@ async fn main() -> anyhow::Result<()> {
         .mount("/:lang/certless/:code/abyss/", certless_abyss_handle)
         .mount("/:lang/certless/:code/abyss/:state", certless_abyss_handle)
         .mount("/:lang/certless/:code/abyss/:state/", rev_fix)
+        // carta permalinks
+        .mount("/:lang/carta/:uuid", carta_handle)
+        .mount("/:lang/carta/:uuid/", rev_fix)
+        // reply feeds
+        .mount("/:lang/feed/:token", feed_handle)
+        .mount("/:lang/feed/:token/", rev_fix)
+        .mount("/:lang/feed/:token/atom", atom_feed_handle)
+        .mount("/:lang/feed/:token/atom/", rev_fix)
         // delete
         .mount("/:lang/delete", fix)
         .mount("/:lang/delete/", delete_handle)
@@ -3,8 +3,7 @@
 diesel::table! {
     cartas (id) {
         id -> Int4,
-        #[max_length = 36]
-        uuid -> Bpchar,
+        uuid -> Uuid,
         parent -> Nullable<Int4>,
         user_id -> Nullable<Int4>,
         #[max_length = 36]
@@ -15,14 +14,33 @@ diesel::table! {
         content -> Varchar,
         #[max_length = 6]
         modification_code -> Bpchar,
-        creation -> Int4,
-        modification -> Nullable<Int4>,
+        creation -> Timestamptz,
+        modification -> Nullable<Timestamptz>,
         #[max_length = 2]
         lang -> Bpchar,
         random_accessible -> Bool,
         reports -> Int4,
         #[max_length = 45]
         ip -> Varchar,
+        visibility -> Int2,
+        expiration -> Nullable<Timestamptz>,
+        views -> Int4,
+        replies -> Int4,
+        last_reply -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    mailbox_reads (user_id) {
+        user_id -> Int4,
+        last_read -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    seen_cartas (user_id, carta_id) {
+        user_id -> Int4,
+        carta_id -> Int4,
     }
 }
 
@@ -32,11 +50,18 @@ diesel::table! {
         certificate_hash -> Bytea,
         #[max_length = 2]
         lang -> Bpchar,
-        creation -> Int4,
+        creation -> Timestamptz,
     }
 }
 
+diesel::joinable!(cartas -> users (user_id));
+diesel::joinable!(mailbox_reads -> users (user_id));
+diesel::joinable!(seen_cartas -> cartas (carta_id));
+diesel::joinable!(seen_cartas -> users (user_id));
+
 diesel::allow_tables_to_appear_in_same_query!(
     cartas,
+    mailbox_reads,
+    seen_cartas,
     users,
 );
@@ -0,0 +1,56 @@
+// The SQLite counterpart of `schema.rs`, used with the `sqlite` feature. Keep both in sync.
+// UUIDs are stored as text, see `crate::backend::SqlUuid`.
+
+diesel::table! {
+    cartas (id) {
+        id -> Integer,
+        uuid -> Text,
+        parent -> Nullable<Integer>,
+        user_id -> Nullable<Integer>,
+        title -> Nullable<Text>,
+        sender -> Nullable<Text>,
+        content -> Text,
+        modification_code -> Text,
+        creation -> TimestamptzSqlite,
+        modification -> Nullable<TimestamptzSqlite>,
+        lang -> Text,
+        random_accessible -> Bool,
+        reports -> Integer,
+        ip -> Text,
+        visibility -> SmallInt,
+        expiration -> Nullable<TimestamptzSqlite>,
+        views -> Integer,
+        replies -> Integer,
+        last_reply -> Nullable<TimestamptzSqlite>,
+    }
+}
+
+diesel::table! {
+    mailbox_reads (user_id) {
+        user_id -> Integer,
+        last_read -> TimestamptzSqlite,
+    }
+}
+
+diesel::table! {
+    seen_cartas (user_id, carta_id) {
+        user_id -> Integer,
+        carta_id -> Integer,
+    }
+}
+
+diesel::table! {
+    users (id) {
+        id -> Integer,
+        certificate_hash -> Binary,
+        lang -> Text,
+        creation -> TimestamptzSqlite,
+    }
+}
+
+diesel::joinable!(cartas -> users (user_id));
+diesel::joinable!(mailbox_reads -> users (user_id));
+diesel::joinable!(seen_cartas -> cartas (carta_id));
+diesel::joinable!(seen_cartas -> users (user_id));
+
+diesel::allow_tables_to_appear_in_same_query!(cartas, mailbox_reads, seen_cartas, users,);
@@ -1,15 +1,17 @@
 use crate::abyss::AbyssState;
 use crate::components::certificate::CERT_HASH_LEN;
-use crate::database::{DatabaseCache, DATABASE, DATABASE_CACHE};
+use crate::consts::default_carta;
+use crate::database::{Database, DatabaseCache, DATABASE_CACHE};
 use crate::i18n::Lang;
 
 use anyhow::{anyhow, Context as _};
 use lazy_static::lazy_static;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, RwLock},
     time::{Duration, Instant},
 };
+use tokio::sync::Mutex;
 
 pub type ClientLookup = HashMap<[u8; CERT_HASH_LEN], (usize, Arc<Mutex<ClientState>>)>;
 pub type Clients = Arc<RwLock<ClientLookup>>;
@@ -28,33 +30,29 @@ pub struct ClientState {
     pub lang: &'static Lang,
 }
 impl ClientState {
-    fn new(
+    async fn new(
         identifier: &[u8; CERT_HASH_LEN],
         lang: &'static Lang,
         certificate: bool,
     ) -> anyhow::Result<Self> {
-        let user = DatabaseCache::get_or_else::<[u8; CERT_HASH_LEN], _>(
-            &DATABASE_CACHE.user,
-            identifier,
-            &|| {
-                let mut database_guard = DATABASE
-                    .lock()
-                    .map_err(|_| anyhow!("failed to lock database mutex"))?;
-
-                let user = database_guard.fetch_user(identifier)?.map_or_else(
-                    || database_guard.insert_user(lang.code.clone(), identifier),
+        let user = DatabaseCache::get_or_else(&DATABASE_CACHE.user, identifier, || {
+            let identifier = *identifier;
+            Database::run(move |connection| {
+                let user = Database::fetch_user(connection, &identifier)?.map_or_else(
+                    || Database::insert_user(connection, lang.code.clone(), &identifier),
                     Ok,
                 )?;
 
                 Ok(user)
-            },
-        )?;
+            })
+        })
+        .await?;
 
         Ok(Self {
             certificate,
             keepalive: Instant::now(),
             id: user.id as _,
-            abyss_state: AbyssState::new(lang),
+            abyss_state: AbyssState::new(lang, default_carta().await?),
             lang,
         })
     }
@@ -73,11 +71,10 @@ impl ClientState {
         self.id
     }
     /// Update language in client database
-    pub fn update_lang(&mut self, lang: &'static Lang) -> anyhow::Result<()> {
-        let mut database_guard = DATABASE
-            .lock()
-            .map_err(|_| anyhow!("failed to lock database mutex"))?;
-        database_guard.change_language(self.id as _, &lang.code)?;
+    pub async fn update_lang(&mut self, lang: &'static Lang) -> anyhow::Result<()> {
+        let id = self.id as _;
+        Database::run(move |connection| Database::change_language(connection, id, &lang.code))
+            .await?;
 
         self.lang = lang;
 
@@ -87,26 +84,29 @@ impl ClientState {
 
 impl ClientState {
     /// Create a new client
-    pub fn init_state(
+    pub async fn init_state(
         identifier: &[u8; CERT_HASH_LEN],
         lang: &'static Lang,
         certificate: bool,
     ) -> anyhow::Result<(usize, Arc<Mutex<Self>>)> {
         log::trace!("creating a new client");
 
-        let mut guard = CLIENTS
-            .write()
-            .map_err(|_| anyhow!("failed locking clients rwlock"))?;
-
         let hash = {
             let mut heap_clone = [0u8; CERT_HASH_LEN];
             heap_clone.copy_from_slice(identifier);
             heap_clone
         };
-        let state = ClientState::new(&hash, lang, certificate)?;
+        let state = ClientState::new(&hash, lang, certificate).await?;
         let id = state.id();
-        let wrapped_state = (id, Arc::new(Mutex::new(state)));
-        guard.insert(hash, wrapped_state.clone());
+
+        // Another request from the same client may have created it in the meantime
+        let mut guard = CLIENTS
+            .write()
+            .map_err(|_| anyhow!("failed locking clients rwlock"))?;
+        let wrapped_state = guard
+            .entry(hash)
+            .or_insert_with(|| (id, Arc::new(Mutex::new(state))))
+            .clone();
 
         log::trace!("created a new client with id {id}");
 
@@ -142,9 +142,10 @@ impl ClientState {
         let to_prune = guard
             .iter()
             .map(|(cert_ref, (id, client))| {
-                let guard = client
-                    .lock()
-                    .map_err(|_| anyhow!("failed to lock client mutex"))?;
+                // A client being handled right now is in use
+                let Ok(guard) = client.try_lock() else {
+                    return Ok(None);
+                };
                 let lifetime = guard.keeaplive().elapsed();
                 Ok(if lifetime > PRUNE_TIME {
                     log::trace!(
@@ -1,12 +1,248 @@
-use std::{
-    cell::RefCell,
-    rc::{Rc, Weak},
-};
+use std::{collections::VecDeque, ops::Index};
 
+/// Handle to a node in a [`Tree`], only meaningful for the tree it came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(usize);
+
+#[derive(Debug)]
+struct Node<C> {
+    value: C,
+    /// `None` designates the root node
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+}
+
+/// An arena-allocated tree. Nodes are never removed, so a [`NodeId`] stays valid for
+/// the lifetime of its tree, and the tree is `Send + Sync` whenever `C` is.
 #[derive(Debug)]
-pub struct TreeBranch<C> {
-    pub node: C,
-    /// `None` designates a root node
-    pub parent: Option<Weak<TreeBranch<C>>>,
-    pub children: RefCell<Vec<Rc<TreeBranch<C>>>>,
+pub struct Tree<C> {
+    nodes: Vec<Node<C>>,
+}
+impl<C> Tree<C> {
+    pub fn new(root: C) -> Self {
+        Self {
+            nodes: vec![Node {
+                value: root,
+                parent: None,
+                children: vec![],
+            }],
+        }
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Append a node as the last child of `parent`
+    pub fn push(&mut self, parent: NodeId, value: C) -> NodeId {
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Node {
+            value,
+            parent: Some(parent),
+            children: vec![],
+        });
+        self.nodes[parent.0].children.push(id);
+        id
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.nodes[id.0].parent
+    }
+
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.nodes[id.0].children
+    }
+
+    /// Other children of this node's parent, in order
+    pub fn siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.parent(id)
+            .map(|parent| self.children(parent))
+            .unwrap_or_default()
+            .iter()
+            .copied()
+            .filter(move |&sibling| sibling != id)
+    }
+
+    /// Ancestors of a node, from its parent up to the root
+    pub fn ancestors(&self, id: NodeId) -> Ancestors<'_, C> {
+        Ancestors {
+            tree: self,
+            next: self.parent(id),
+        }
+    }
+
+    /// Pre-order depth-first traversal of the subtree rooted at a node
+    pub fn dfs(&self, id: NodeId) -> Dfs<'_, C> {
+        Dfs {
+            tree: self,
+            stack: vec![id],
+        }
+    }
+
+    /// Breadth-first traversal of the subtree rooted at a node
+    pub fn bfs(&self, id: NodeId) -> Bfs<'_, C> {
+        Bfs {
+            tree: self,
+            queue: VecDeque::from([id]),
+        }
+    }
+
+    /// First node in depth-first order whose value matches
+    pub fn find(&self, predicate: impl Fn(&C) -> bool) -> Option<NodeId> {
+        self.dfs(self.root()).find(|&id| predicate(&self[id]))
+    }
+}
+impl<C> Index<NodeId> for Tree<C> {
+    type Output = C;
+
+    fn index(&self, id: NodeId) -> &Self::Output {
+        &self.nodes[id.0].value
+    }
+}
+
+pub struct Ancestors<'a, C> {
+    tree: &'a Tree<C>,
+    next: Option<NodeId>,
+}
+impl<C> Iterator for Ancestors<'_, C> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.next?;
+        self.next = self.tree.parent(id);
+        Some(id)
+    }
+}
+
+pub struct Dfs<'a, C> {
+    tree: &'a Tree<C>,
+    stack: Vec<NodeId>,
+}
+impl<C> Iterator for Dfs<'_, C> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.stack.pop()?;
+        self.stack
+            .extend(self.tree.children(id).iter().rev().copied());
+        Some(id)
+    }
+}
+
+pub struct Bfs<'a, C> {
+    tree: &'a Tree<C>,
+    queue: VecDeque<NodeId>,
+}
+impl<C> Iterator for Bfs<'_, C> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.queue.pop_front()?;
+        self.queue.extend(self.tree.children(id).iter().copied());
+        Some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Carta;
+
+    /// ```text
+    /// 0
+    /// ├─ 1
+    /// │  ├─ 3
+    /// │  └─ 4
+    /// └─ 2
+    ///    └─ 5
+    /// ```
+    fn tree() -> (Tree<u32>, Vec<NodeId>) {
+        let mut tree = Tree::new(0);
+        let root = tree.root();
+        let one = tree.push(root, 1);
+        let two = tree.push(root, 2);
+        let three = tree.push(one, 3);
+        let four = tree.push(one, 4);
+        let five = tree.push(two, 5);
+        (tree, vec![root, one, two, three, four, five])
+    }
+
+    fn values(tree: &Tree<u32>, nodes: impl Iterator<Item = NodeId>) -> Vec<u32> {
+        nodes.map(|node| tree[node]).collect()
+    }
+
+    #[test]
+    fn push() {
+        let (tree, nodes) = tree();
+        assert_eq!(tree.len(), 6);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.parent(nodes[0]), None);
+        assert_eq!(tree.parent(nodes[4]), Some(nodes[1]));
+        assert_eq!(tree.children(nodes[1]), [nodes[3], nodes[4]]);
+        assert!(tree.children(nodes[5]).is_empty());
+    }
+
+    #[test]
+    fn dfs() {
+        let (tree, nodes) = tree();
+        assert_eq!(values(&tree, tree.dfs(nodes[0])), [0, 1, 3, 4, 2, 5]);
+        assert_eq!(values(&tree, tree.dfs(nodes[2])), [2, 5]);
+    }
+
+    #[test]
+    fn bfs() {
+        let (tree, nodes) = tree();
+        assert_eq!(values(&tree, tree.bfs(nodes[0])), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(values(&tree, tree.bfs(nodes[1])), [1, 3, 4]);
+    }
+
+    #[test]
+    fn ancestors() {
+        let (tree, nodes) = tree();
+        assert_eq!(values(&tree, tree.ancestors(nodes[4])), [1, 0]);
+        assert_eq!(tree.ancestors(nodes[0]).count(), 0);
+    }
+
+    #[test]
+    fn siblings() {
+        let (tree, nodes) = tree();
+        assert_eq!(values(&tree, tree.siblings(nodes[3])), [4]);
+        assert_eq!(values(&tree, tree.siblings(nodes[1])), [2]);
+        assert_eq!(tree.siblings(nodes[5]).count(), 0);
+        assert_eq!(tree.siblings(nodes[0]).count(), 0);
+    }
+
+    #[test]
+    fn find() {
+        let (tree, nodes) = tree();
+        assert_eq!(tree.find(|&value| value == 4), Some(nodes[4]));
+        assert_eq!(tree.find(|&value| value > 2), Some(nodes[3]));
+        assert_eq!(tree.find(|&value| value > 5), None);
+    }
+
+    #[test]
+    fn deep_traversal() {
+        let mut tree = Tree::new(0);
+        let mut node = tree.root();
+        for value in 1..100_000 {
+            node = tree.push(node, value);
+        }
+        assert_eq!(tree.dfs(tree.root()).count(), 100_000);
+        assert_eq!(tree.bfs(tree.root()).count(), 100_000);
+        assert_eq!(tree.ancestors(node).count(), 99_999);
+    }
+
+    #[test]
+    fn carta_tree_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Tree<Carta>>();
+    }
 }